@@ -0,0 +1,189 @@
+use axum::{
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use std::{io, path::PathBuf};
+use tokio::{fs, io::AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::{config::Config, db::AppState, gc};
+
+/// Build outputs users can download: the extension they are stored with,
+/// the content type they are served with and the name they download as.
+const KINDS: &[(&str, &str, &str)] = &[
+    ("wasm", "application/wasm", "main.wasm"),
+    ("bin", "application/octet-stream", "main"),
+    ("exe", "application/vnd.microsoft.portable-executable", "main.exe"),
+    ("rlib", "application/octet-stream", "libmain.rlib"),
+    ("s", "text/plain; charset=utf-8", "main.s"),
+];
+
+/// When an artifact stored now expires. Downloads stop working then, and
+/// the next sweep removes it.
+pub fn expires_at(config: &Config) -> DateTime<Utc> {
+    Utc::now() + config.artifact_ttl
+}
+
+/// Whether the artifact at `path` is past `ARTIFACT_TTL_SECS`.
+async fn expired(config: &Config, path: &std::path::Path) -> bool {
+    let modified = fs::metadata(path).await.and_then(|metadata| metadata.modified());
+    modified.is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > config.artifact_ttl))
+}
+
+fn dir(config: &Config) -> PathBuf {
+    config.temp_dir.join("artifacts")
+}
+
+/// Creates `path` under the artifacts directory for the server's user alone.
+/// Runs can get into the temp directory, and their users must not reach
+/// what other runs stored.
+async fn private_dir(config: &Config, path: &std::path::Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(path).await?;
+    // It may be left over from before artifacts were kept private.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir(config), std::fs::Permissions::from_mode(0o700)).await?;
+    }
+    Ok(())
+}
+
+/// Copies at most `limit` bytes of `file` into a new file at `target` that
+/// belongs to the server's user and only it can read. A symlink or other
+/// special file the run left in its place is refused, not followed.
+async fn copy_private(file: &std::path::Path, target: &std::path::Path, limit: u64) -> io::Result<u64> {
+    let mut options = fs::OpenOptions::new();
+    options.read(true);
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK);
+    let source = options.open(file).await?;
+    if !source.metadata().await?.is_file() {
+        return Err(io::Error::other("not a regular file"));
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut copy = options.open(target).await?;
+    let size = tokio::io::copy(&mut tokio::io::AsyncReadExt::take(source, limit), &mut copy).await?;
+    copy.flush().await?;
+    Ok(size)
+}
+
+/// Copies a build output out of the run's workspace, which is removed when
+/// the run ends, and returns the path it can be downloaded from and its size.
+pub async fn store(config: &Config, file: &std::path::Path, extension: &str) -> io::Result<(String, u64)> {
+    let name = format!("{}.{}", Uuid::new_v4(), extension);
+    private_dir(config, &dir(config)).await?;
+    let size = copy_private(file, &dir(config).join(&name), u64::MAX).await?;
+    gc::stored(size);
+    Ok((format!("/artifacts/{}", name), size))
+}
+
+/// Copies a file the program wrote out of the run's workspace and returns
+/// the path it can be downloaded from. It keeps its own name, in a directory
+/// of its own, but no more than the size it had when it was collected.
+pub async fn store_file(config: &Config, file: &std::path::Path) -> io::Result<String> {
+    let id = Uuid::new_v4();
+    let name = file.file_name().ok_or_else(|| io::Error::other("not a file"))?;
+    let target = dir(config).join("files").join(id.to_string());
+    let limit = fs::symlink_metadata(file).await?.len();
+    private_dir(config, &target).await?;
+    let size = copy_private(file, &target.join(name), limit).await?;
+    gc::stored(size);
+    Ok(format!("/artifacts/files/{}", id))
+}
+
+// GET /artifacts/:name
+#[utoipa::path(
+    get,
+    path = "/artifacts/{name}",
+    params(
+        ("name" = String, Path, description = "Artifact name as returned in a run result")
+    ),
+    responses(
+        (status = 200, description = "The artifact"),
+        (status = 404, description = "Artifact not found"),
+        (status = 410, description = "Artifact expired")
+    ),
+    tag = "artifacts"
+)]
+pub async fn download(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let not_found = || (StatusCode::NOT_FOUND, "Artifact not found".to_string());
+
+    // Names are always `<uuid>.<kind>`, which also rules out path traversal.
+    let (id, extension) = name.split_once('.').ok_or_else(not_found)?;
+    Uuid::parse_str(id).map_err(|_| not_found())?;
+    let (_, content_type, download_name) = KINDS
+        .iter()
+        .find(|(kind, _, _)| *kind == extension)
+        .ok_or_else(not_found)?;
+
+    let path = dir(&state.config).join(&name);
+    if expired(&state.config, &path).await {
+        return Err((StatusCode::GONE, "Artifact expired".to_string()));
+    }
+    let bytes = fs::read(path).await.map_err(|_| not_found())?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", download_name)),
+        ],
+        bytes,
+    ))
+}
+
+// GET /artifacts/files/:id
+#[utoipa::path(
+    get,
+    path = "/artifacts/files/{id}",
+    params(
+        ("id" = String, Path, description = "File id as returned in a run result")
+    ),
+    responses(
+        (status = 200, description = "The file, under the name the program gave it"),
+        (status = 404, description = "File not found"),
+        (status = 410, description = "File expired")
+    ),
+    tag = "artifacts"
+)]
+pub async fn download_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let not_found = || (StatusCode::NOT_FOUND, "File not found".to_string());
+
+    let id = Uuid::parse_str(&id).map_err(|_| not_found())?;
+    let path = dir(&state.config).join("files").join(id.to_string());
+    if expired(&state.config, &path).await {
+        return Err((StatusCode::GONE, "File expired".to_string()));
+    }
+    let mut entries = fs::read_dir(path).await.map_err(|_| not_found())?;
+    let entry = entries.next_entry().await.ok().flatten().ok_or_else(not_found)?;
+    let bytes = fs::read(entry.path()).await.map_err(|_| not_found())?;
+
+    // The name is the program's choice; keep it to what fits in a quoted string.
+    let name: String = entry
+        .file_name()
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' { c } else { '_' })
+        .collect();
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", name)),
+        ],
+        bytes,
+    ))
+}
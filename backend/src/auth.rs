@@ -4,7 +4,6 @@ use axum::{
     response::{IntoResponse, Json},
 };
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
 use argon2::{
     password_hash::{
         rand_core::OsRng,
@@ -60,27 +59,52 @@ where
             return Err((StatusCode::UNAUTHORIZED, "Invalid token format".to_string()));
         }
 
-        let token = &auth_header[7..];
-        let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "secret".to_string());
+        verify(&auth_header[7..])
+    }
+}
 
-        let token_data = decode::<Claims>(
-            token,
-            &DecodingKey::from_secret(secret.as_bytes()),
-            &Validation::default(),
-        )
-        .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Invalid token: {}", e)))?;
+/// The user behind a request that may also be made anonymously. A token
+/// that is sent has to be valid.
+pub struct OptionalClaims(pub Option<Claims>);
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for OptionalClaims
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
 
-        Ok(token_data.claims)
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if !parts.headers.contains_key(AUTHORIZATION) {
+            return Ok(OptionalClaims(None));
+        }
+        Claims::from_request_parts(parts, state).await.map(|claims| OptionalClaims(Some(claims)))
     }
 }
 
+/// Checks a JWT issued by `login_handler`, e.g. one passed where headers
+/// can't be set, such as when opening a WebSocket.
+pub fn verify(token: &str) -> Result<Claims, (StatusCode, String)> {
+    let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "secret".to_string());
+
+    let token_data = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Invalid token: {}", e)))?;
+
+    Ok(token_data.claims)
+}
+
 #[utoipa::path(
     post,
     path = "/auth/register",
     request_body = RegisterRequest,
     responses(
         (status = 201, description = "User registered successfully"),
-        (status = 409, description = "Username already exists")
+        (status = 409, description = "Username already exists"),
+        (status = 429, description = "Too many attempts from this address, see Retry-After")
     ),
     tag = "auth"
 )]
@@ -126,7 +150,8 @@ pub async fn register_handler(
     request_body = LoginRequest,
     responses(
         (status = 200, description = "Login successful", body = AuthResponse),
-        (status = 401, description = "Invalid credentials")
+        (status = 401, description = "Invalid credentials"),
+        (status = 429, description = "Too many attempts from this address, see Retry-After")
     ),
     tag = "auth"
 )]
@@ -0,0 +1,113 @@
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use std::{io, path::PathBuf};
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+use utoipa::ToSchema;
+
+use crate::wasm;
+
+static TARGETS: OnceCell<Vec<String>> = OnceCell::const_new();
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct BuildOptions {
+    #[serde(default)]
+    pub output: BuildOutput,
+}
+
+/// What a build produces.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildOutput {
+    /// An executable for the target.
+    #[default]
+    Binary,
+    /// The code compiled as a library crate.
+    Rlib,
+    /// A WASI module, built for `wasm32-wasip1` unless another wasm target
+    /// is chosen.
+    Wasm,
+    /// Assembly (`.s`) of the code.
+    Asm,
+}
+
+impl BuildOptions {
+    /// `rustc` arguments for the output, and the kind the artifact is stored
+    /// as (see `artifacts`).
+    pub fn rustc_args(self, target: Option<&str>) -> Result<(Vec<String>, &'static str), String> {
+        let target = match (self.output, target) {
+            (BuildOutput::Wasm, None) => Some(wasm::TARGET),
+            (BuildOutput::Wasm, Some(target)) if !target.starts_with("wasm") => {
+                return Err(format!("{} is not a wasm target", target));
+            }
+            (_, target) => target,
+        };
+        let mut args = Vec::new();
+        if let Some(target) = target {
+            args.extend(["--target".to_string(), target.to_string()]);
+        }
+        let kind = match self.output {
+            BuildOutput::Binary | BuildOutput::Wasm => match target {
+                Some(target) if target.starts_with("wasm") => "wasm",
+                Some(target) if target.contains("windows") => "exe",
+                None if cfg!(target_os = "windows") => "exe",
+                _ => "bin",
+            },
+            BuildOutput::Rlib => {
+                args.push("--crate-type=rlib".to_string());
+                "rlib"
+            }
+            BuildOutput::Asm => {
+                args.push("--emit=asm".to_string());
+                "s"
+            }
+        };
+        Ok((args, kind))
+    }
+}
+
+/// Targets with a standard library installed for the stable toolchain.
+pub async fn installed_targets() -> io::Result<&'static [String]> {
+    TARGETS.get_or_try_init(find_targets).await.map(Vec::as_slice)
+}
+
+async fn find_targets() -> io::Result<Vec<String>> {
+    let output = Command::new("rustc").args(["--print", "sysroot"]).output().await?;
+    if !output.status.success() {
+        return Err(io::Error::other("rustc --print sysroot failed"));
+    }
+    let rustlib = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()).join("lib").join("rustlib");
+
+    // Every installed target has `lib/rustlib/<target>/lib`.
+    let mut targets = Vec::new();
+    let mut entries = tokio::fs::read_dir(rustlib).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if tokio::fs::metadata(entry.path().join("lib")).await.is_ok_and(|m| m.is_dir()) {
+            targets.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    targets.sort();
+    Ok(targets)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TargetsResponse {
+    /// Targets a build may choose with `target`.
+    pub targets: Vec<String>,
+}
+
+// GET /targets
+#[utoipa::path(
+    get,
+    path = "/targets",
+    responses(
+        (status = 200, description = "Installed compilation targets", body = TargetsResponse)
+    ),
+    tag = "compiler"
+)]
+pub async fn targets_handler() -> Result<impl IntoResponse, (StatusCode, String)> {
+    let targets = installed_targets()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(TargetsResponse { targets: targets.to_vec() }))
+}
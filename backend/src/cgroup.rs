@@ -0,0 +1,227 @@
+use serde::Serialize;
+use std::{
+    ffi::CString,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::process::Command;
+use uuid::Uuid;
+
+/// Controllers the per-run limits need.
+const CONTROLLERS: [&str; 3] = ["memory", "pids", "cpu"];
+
+/// Scheduling period `cpu.max` quotas are given for, in microseconds.
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Leaf the server moves itself into, since a cgroup with processes of its
+/// own can't hand controllers down to its children.
+const SERVER_LEAF: &str = "server";
+
+/// Limits applied to each run's cgroup. `0` means unlimited.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// `memory.max`, in bytes. Swap is disabled.
+    pub memory_max: u64,
+    /// `pids.max`: processes and threads alive at once.
+    pub pids_max: u64,
+    /// `cpu.max`, in CPUs' worth of time.
+    pub cpus: f64,
+}
+
+/// What a run's cgroup accounted for, covering every process the program
+/// started.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Accounting {
+    /// Peak memory use of the whole group, page cache included. Needs
+    /// Linux 5.19.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_peak_kb: Option<u64>,
+    pub cpu_usage_ms: u64,
+    pub cpu_user_ms: u64,
+    pub cpu_system_ms: u64,
+    /// Scheduling periods in which the group used up its `cpu.max` quota.
+    pub throttled_periods: u64,
+    pub throttled_ms: u64,
+    /// Processes killed for going over `memory.max`.
+    pub oom_kills: u64,
+}
+
+/// Picks the cgroup v2 subtree runs go in from `CGROUP_ROOT`: a path, `off`,
+/// or `auto` for the server's own cgroup when it may manage it (e.g. a
+/// systemd service with `Delegate=yes`). The root cgroup is never used.
+pub fn resolve(setting: &str) -> Option<PathBuf> {
+    match setting {
+        "off" => None,
+        "auto" => own_cgroup().filter(|dir| {
+            dir != Path::new("/sys/fs/cgroup")
+                && dir.join("cgroup.controllers").exists()
+                && fs::OpenOptions::new().write(true).open(dir.join("cgroup.subtree_control")).is_ok()
+        }),
+        path => Some(PathBuf::from(path)),
+    }
+}
+
+/// Prepares `root` for per-run cgroups: moves the server out of it if it
+/// lives there, enables the controllers and removes groups a previous
+/// instance left behind.
+pub fn delegate(root: &Path) -> io::Result<()> {
+    if !root.join("cgroup.kill").exists() {
+        return Err(io::Error::other("not a cgroup v2 group with cgroup.kill (Linux 5.14+)"));
+    }
+    if own_cgroup().as_deref() == Some(root) {
+        let leaf = root.join(SERVER_LEAF);
+        match fs::create_dir(&leaf) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+        fs::write(leaf.join("cgroup.procs"), "0")?;
+    }
+
+    let available = fs::read_to_string(root.join("cgroup.controllers"))?;
+    let available: Vec<&str> = available.split_whitespace().collect();
+    let (enabled, missing): (Vec<&str>, Vec<&str>) = CONTROLLERS.iter().partition(|c| available.contains(c));
+    for controller in &enabled {
+        fs::write(root.join("cgroup.subtree_control"), format!("+{}", controller))?;
+    }
+    if !missing.is_empty() {
+        eprintln!("cgroup controllers not available, their limits won't apply: {}", missing.join(", "));
+    }
+
+    for entry in fs::read_dir(root)?.flatten() {
+        if entry.file_name().to_string_lossy().starts_with("run-") {
+            let _ = fs::write(entry.path().join("cgroup.kill"), "1");
+            let _ = fs::remove_dir(entry.path());
+        }
+    }
+    Ok(())
+}
+
+fn own_cgroup() -> Option<PathBuf> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/')))
+}
+
+/// The cgroup of a single run.
+pub struct Group {
+    dir: PathBuf,
+    procs: CString,
+}
+
+impl Group {
+    pub async fn create(root: &Path, limits: &Limits) -> io::Result<Self> {
+        let dir = root.join(format!("run-{}", Uuid::new_v4()));
+        tokio::fs::create_dir(&dir).await?;
+        let procs = CString::new(dir.join("cgroup.procs").to_string_lossy().into_owned())
+            .map_err(io::Error::other)?;
+        let group = Self { dir, procs };
+
+        let max = |value: u64| if value == 0 { "max".to_string() } else { value.to_string() };
+        let quota = (limits.cpus * CPU_PERIOD_US as f64) as u64;
+        let settings = [
+            ("memory.max", max(limits.memory_max)),
+            ("memory.swap.max", "0".to_string()),
+            ("pids.max", max(limits.pids_max)),
+            ("cpu.max", format!("{} {}", max(quota), CPU_PERIOD_US)),
+        ];
+        for (file, value) in settings {
+            match write(&group.dir.join(file), &value).await {
+                // The controller isn't enabled; `delegate` already said so.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    group.finish().await;
+                    return Err(e);
+                }
+                Ok(()) => {}
+            }
+        }
+        Ok(group)
+    }
+
+    /// Makes `command` start in this group, so everything it runs is
+    /// limited and accounted here from the first instruction.
+    pub fn join(&self, command: &mut Command) {
+        #[cfg(unix)]
+        {
+            let procs = self.procs.clone();
+            // SAFETY: only open/write/close between fork and exec.
+            unsafe {
+                command.pre_exec(move || {
+                    let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    // "0" is the writing process.
+                    let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                    let error = io::Error::last_os_error();
+                    libc::close(fd);
+                    if written != 1 {
+                        return Err(error);
+                    }
+                    Ok(())
+                });
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = command;
+    }
+
+    /// Processes in the group right now.
+    pub async fn pids(&self) -> Vec<u32> {
+        let procs = tokio::fs::read_to_string(self.dir.join("cgroup.procs")).await.unwrap_or_default();
+        procs.lines().filter_map(|line| line.parse().ok()).collect()
+    }
+
+    /// Kills every process in the group.
+    pub async fn kill(&self) {
+        let _ = write(&self.dir.join("cgroup.kill"), "1").await;
+    }
+
+    /// Kills whatever is left, reads the accounting and removes the group.
+    pub async fn finish(self) -> Accounting {
+        self.kill().await;
+        // Killed processes leave the group asynchronously, and a populated
+        // group can't be removed.
+        for _ in 0..100 {
+            let events = tokio::fs::read_to_string(self.dir.join("cgroup.events")).await.unwrap_or_default();
+            if events.lines().any(|line| line == "populated 0") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let read = |file: &str| std::fs::read_to_string(self.dir.join(file)).unwrap_or_default();
+        let cpu = read("cpu.stat");
+        let memory_events = read("memory.events");
+        let accounting = Accounting {
+            memory_peak_kb: read("memory.peak").trim().parse::<u64>().ok().map(|bytes| bytes / 1024),
+            cpu_usage_ms: stat(&cpu, "usage_usec") / 1000,
+            cpu_user_ms: stat(&cpu, "user_usec") / 1000,
+            cpu_system_ms: stat(&cpu, "system_usec") / 1000,
+            throttled_periods: stat(&cpu, "nr_throttled"),
+            throttled_ms: stat(&cpu, "throttled_usec") / 1000,
+            oom_kills: stat(&memory_events, "oom_kill"),
+        };
+        if let Err(e) = tokio::fs::remove_dir(&self.dir).await {
+            eprintln!("Failed to remove cgroup {}: {}", self.dir.display(), e);
+        }
+        accounting
+    }
+}
+
+/// Writes an existing cgroup file. Interface files can't be created, so a
+/// missing one (controller not enabled) is `NotFound`.
+async fn write(path: &Path, value: &str) -> io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    file.write_all(value.as_bytes()).await
+}
+
+/// A value from a flat-keyed cgroup file such as `cpu.stat`.
+fn stat(file: &str, key: &str) -> u64 {
+    file.lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
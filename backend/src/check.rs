@@ -0,0 +1,157 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{config::Config, db::AppState, diagnostics::Diagnostic, executor::Workspace};
+
+/// Name the submitted file is reported under, matching the editor tab.
+const DISPLAY_NAME: &str = "main.rs";
+
+#[derive(Deserialize, ToSchema)]
+pub struct CheckRequest {
+    pub code: String,
+    /// Identifies the editor sending the request. A new check for the same
+    /// session cancels the one still running.
+    pub session_id: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CheckResponse {
+    /// No errors were reported.
+    pub success: bool,
+    pub diagnostics: Vec<Diagnostic>,
+    pub timed_out: bool,
+    pub duration_ms: u64,
+}
+
+/// Checks currently running, by session id, so a newer version of the code
+/// can cancel the one it replaces.
+#[derive(Clone, Default)]
+pub struct Sessions(Arc<Mutex<HashMap<String, (Uuid, CancellationToken)>>>);
+
+impl Sessions {
+    fn start(&self, session: &str) -> (Uuid, CancellationToken) {
+        let id = Uuid::new_v4();
+        let token = CancellationToken::new();
+        let previous = self
+            .0
+            .lock()
+            .unwrap()
+            .insert(session.to_string(), (id, token.clone()));
+        if let Some((_, previous)) = previous {
+            previous.cancel();
+        }
+        (id, token)
+    }
+
+    fn finish(&self, session: &str, id: Uuid) {
+        let mut sessions = self.0.lock().unwrap();
+        if sessions.get(session).is_some_and(|(current, _)| *current == id) {
+            sessions.remove(session);
+        }
+    }
+}
+
+// POST /check
+#[utoipa::path(
+    post,
+    path = "/check",
+    request_body = CheckRequest,
+    responses(
+        (status = 200, description = "Diagnostics for the code", body = CheckResponse),
+        (status = 409, description = "Cancelled by a newer check for the same session")
+    ),
+    tag = "compiler"
+)]
+pub async fn check_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<CheckRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let session = payload.session_id.map(|session| {
+        let (id, token) = state.checks.start(&session);
+        (session, id, token)
+    });
+    let cancel = session.as_ref().map(|(_, _, token)| token.clone()).unwrap_or_default();
+
+    // Spawned so the temp files are cleaned up even if the client goes away.
+    let outcome = tokio::spawn(check(state.config.clone(), payload.code, cancel))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if let Some((session, id, _)) = session {
+        state.checks.finish(&session, id);
+    }
+
+    match outcome? {
+        Some(response) => Ok(Json(response)),
+        None => Err((StatusCode::CONFLICT, "Superseded by a newer check".to_string())),
+    }
+}
+
+/// Type-checks `code` without generating code or running anything. Returns
+/// `None` if `cancel` fires first.
+pub async fn check(
+    config: Arc<Config>,
+    code: String,
+    cancel: CancellationToken,
+) -> Result<Option<CheckResponse>, (StatusCode, String)> {
+    let internal = |e: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+
+    let workspace = Workspace::create(&config).await.map_err(internal)?;
+    tokio::fs::write(&workspace.source, code).await.map_err(internal)?;
+    if let Err(e) = workspace.hand_over().await {
+        workspace.cleanup().await;
+        return Err(internal(e));
+    }
+
+    let started = Instant::now();
+    let mut rustc = Command::new("rustc");
+    rustc
+        .args(["--emit=metadata", "--error-format=json", "--crate-type=bin"])
+        .arg(&workspace.source)
+        .arg("-o")
+        .arg(workspace.file("rmeta"))
+        .kill_on_drop(true);
+    let enclosure = match workspace.confine_compiler(&config, &mut rustc).await {
+        Ok(enclosure) => enclosure,
+        Err(message) => {
+            workspace.cleanup().await;
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, message));
+        }
+    };
+    let output = tokio::select! {
+        output = tokio::time::timeout(config.check_timeout, rustc.output()) => Some(output),
+        _ = cancel.cancelled() => None,
+    };
+    let duration_ms = started.elapsed().as_millis() as u64;
+    enclosure.finish().await;
+    workspace.cleanup().await;
+
+    let Some(output) = output else {
+        return Ok(None);
+    };
+    let Ok(output) = output else {
+        return Ok(Some(CheckResponse { success: false, diagnostics: Vec::new(), timed_out: true, duration_ms }));
+    };
+    let output = output.map_err(internal)?;
+
+    let source = workspace.source.to_string_lossy();
+    let diagnostics = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter_map(|line| Diagnostic::from_json_line(&line.replace(source.as_ref(), DISPLAY_NAME)))
+        .collect();
+
+    Ok(Some(CheckResponse {
+        success: output.status.success(),
+        diagnostics,
+        timed_out: false,
+        duration_ms,
+    }))
+}
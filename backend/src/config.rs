@@ -0,0 +1,154 @@
+use std::{env, ops::Range, path::PathBuf, str::FromStr, time::Duration};
+
+use crate::{
+    cgroup,
+    output::{LimitAction, OutputLimits},
+    quota::{Quota, Quotas},
+    ratelimit::{Policies, Policy},
+};
+
+/// Server-wide settings, read once from the environment (and `.env`) at startup.
+#[derive(Clone)]
+pub struct Config {
+    /// Directory where sources and binaries for each run are written.
+    pub temp_dir: PathBuf,
+    /// Age after which leftover workspaces and artifacts are removed.
+    pub temp_max_age: Duration,
+    /// How long downloadable artifacts are kept.
+    pub artifact_ttl: Duration,
+    /// How often the temp directory is swept.
+    pub gc_interval: Duration,
+    /// Most bytes the temp directory may hold before new runs are refused;
+    /// `0` means no quota.
+    pub temp_quota: u64,
+    /// Wall-clock limit for the program itself. Fuzzing sessions are bounded
+    /// by their requested duration instead.
+    pub run_timeout: Duration,
+    /// Toolchain used for modes that need unstable features (Miri, sanitizers, ...).
+    pub nightly_toolchain: String,
+    /// libFuzzer runtime archive (e.g. `libclang_rt.fuzzer-x86_64.a`). The
+    /// fuzz mode is unavailable without it.
+    pub libfuzzer_path: Option<PathBuf>,
+    /// Longest fuzzing session a request may ask for.
+    pub fuzz_max_duration: Duration,
+    /// How long a program may wait for input on stdin before it's stopped.
+    pub stdin_timeout: Option<Duration>,
+    /// Most program input queued but not yet read by the program, in bytes.
+    pub stdin_buffer_limit: usize,
+    /// Largest `/compile` request body, which includes an uploaded stdin file.
+    pub upload_limit: usize,
+    /// cgroup v2 subtree each run gets a cgroup in, if there is one to use.
+    /// Cleared at startup if the server can't set it up.
+    pub cgroup_root: Option<PathBuf>,
+    pub cgroup_limits: cgroup::Limits,
+    /// Run each program in a network namespace of its own. Cleared at
+    /// startup if the server can't create one.
+    pub network_isolation: bool,
+    /// User ids programs run as, one per run at a time, so they can't reach
+    /// each other's directories. Cleared at startup if the server can't
+    /// switch users.
+    pub run_uids: Option<Range<u32>>,
+    /// Most files collected from a run's working directory after it exits.
+    pub files_max_count: usize,
+    /// Most bytes of files collected from a run's working directory.
+    pub files_max_bytes: u64,
+    /// Caps on how much a single run may print, and how fast.
+    pub output_limits: OutputLimits,
+    /// Linear memory cap for programs run in the wasm mode, in bytes.
+    pub wasm_memory_limit: usize,
+    /// rust-analyzer binary used for `/lsp` sessions.
+    pub rust_analyzer: String,
+    /// Most language server sessions running at once.
+    pub lsp_max_sessions: usize,
+    /// How long an `/lsp` session may go without a client message.
+    pub lsp_idle_timeout: Duration,
+    /// How long a `/ws` run outlives its connection, waiting for the client
+    /// to attach again.
+    pub session_grace: Duration,
+    /// Most run events kept for clients attaching again, in bytes.
+    pub session_buffer: usize,
+    /// Most output kept with a run in the history, in bytes.
+    pub history_output_limit: usize,
+    /// What users of each role may use a day.
+    pub quotas: Quotas,
+    /// Token buckets for runs and logins.
+    pub rate_limits: Policies,
+    /// How often WebSocket clients are pinged.
+    pub ws_ping_interval: Option<Duration>,
+    /// How long a WebSocket may go without messages either way.
+    pub ws_idle_timeout: Option<Duration>,
+    /// Limit for a `/check` run of `rustc`.
+    pub check_timeout: Duration,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            temp_dir: PathBuf::from(env_or("TEMP_DIR", "temp".to_string())),
+            temp_max_age: Duration::from_secs(env_or("TEMP_MAX_AGE_SECS", 3600)),
+            artifact_ttl: Duration::from_secs(env_or("ARTIFACT_TTL_SECS", 600)),
+            gc_interval: Duration::from_secs(env_or("GC_INTERVAL_SECS", 300)),
+            temp_quota: env_or("TEMP_QUOTA_MB", 2048) * 1024 * 1024,
+            run_timeout: Duration::from_secs(env_or("RUN_TIMEOUT_SECS", 10)),
+            nightly_toolchain: env_or("NIGHTLY_TOOLCHAIN", "nightly".to_string()),
+            libfuzzer_path: env::var("LIBFUZZER_PATH").ok().map(PathBuf::from),
+            fuzz_max_duration: Duration::from_secs(env_or("FUZZ_MAX_SECS", 60)),
+            stdin_timeout: Some(Duration::from_secs(env_or("STDIN_TIMEOUT_SECS", 60))).filter(|limit| !limit.is_zero()),
+            stdin_buffer_limit: env_or("STDIN_BUFFER_KB", 1024) * 1024,
+            upload_limit: env_or("UPLOAD_MAX_MB", 64) * 1024 * 1024,
+            cgroup_root: cgroup::resolve(&env_or("CGROUP_ROOT", "auto".to_string())),
+            cgroup_limits: cgroup::Limits {
+                memory_max: env_or("CGROUP_MEMORY_MB", 512) * 1024 * 1024,
+                pids_max: env_or("CGROUP_PIDS_MAX", 64),
+                cpus: env_or("CGROUP_CPUS", 1.0),
+            },
+            network_isolation: env_or("NETWORK_ISOLATION", true),
+            run_uids: Some(env_or("RUN_UID_BASE", 100_000))
+                .map(|base: u32| base..base.saturating_add(env_or("RUN_UIDS", 1000)))
+                .filter(|uids| !uids.is_empty()),
+            files_max_count: env_or("FILES_MAX_COUNT", 16),
+            files_max_bytes: env_or("FILES_MAX_KB", 10 * 1024) * 1024,
+            output_limits: OutputLimits {
+                max_bytes: env_or("OUTPUT_MAX_KB", 1024) * 1024,
+                rate: env_or("OUTPUT_RATE_KB", 256) * 1024,
+                action: env_or("OUTPUT_LIMIT_ACTION", LimitAction::Kill),
+            },
+            wasm_memory_limit: env_or("WASM_MEMORY_MB", 256) * 1024 * 1024,
+            rust_analyzer: env_or("RUST_ANALYZER", "rust-analyzer".to_string()),
+            lsp_max_sessions: env_or("LSP_MAX_SESSIONS", 4),
+            lsp_idle_timeout: Duration::from_secs(env_or("LSP_IDLE_SECS", 600)),
+            check_timeout: Duration::from_secs(env_or("CHECK_TIMEOUT_SECS", 5)),
+            session_grace: Duration::from_secs(env_or("SESSION_GRACE_SECS", 30)),
+            session_buffer: env_or("SESSION_BUFFER_KB", 1024) * 1024,
+            history_output_limit: env_or("HISTORY_OUTPUT_KB", 64) * 1024,
+            quotas: Quotas {
+                user: quota("USER", 600, 500, 256),
+                admin: quota("ADMIN", 0, 0, 0),
+            },
+            rate_limits: Policies {
+                anonymous: Policy::new(env_or("RATE_ANON_PER_MIN", 30.0), env_or("RATE_ANON_BURST", 10.0)),
+                user: Policy::new(env_or("RATE_USER_PER_MIN", 120.0), env_or("RATE_USER_BURST", 30.0)),
+                login: Policy::new(env_or("RATE_LOGIN_PER_MIN", 10.0), env_or("RATE_LOGIN_BURST", 5.0)),
+            },
+            ws_ping_interval: Some(Duration::from_secs(env_or("WS_PING_SECS", 20))).filter(|interval| !interval.is_zero()),
+            ws_idle_timeout: Some(Duration::from_secs(env_or("WS_IDLE_SECS", 600))).filter(|timeout| !timeout.is_zero()),
+        }
+    }
+}
+
+/// The `QUOTA_<ROLE>_*` settings, where `0` means no limit.
+fn quota(role: &str, cpu_secs: u64, runs: u64, artifact_mb: u64) -> Quota {
+    let limit = |name: &str, default: u64| Some(env_or(&format!("QUOTA_{}_{}", role, name), default)).filter(|&limit| limit != 0);
+    Quota {
+        cpu_ms: limit("CPU_SECS", cpu_secs).map(|secs| secs * 1000),
+        runs: limit("RUNS", runs),
+        artifact_bytes: limit("ARTIFACT_MB", artifact_mb).map(|mb| mb * 1024 * 1024),
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
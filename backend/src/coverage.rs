@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use std::{io, path::{Path, PathBuf}};
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+
+/// Name the submitted file is reported under, matching the editor tab.
+const DISPLAY_NAME: &str = "main.rs";
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct CoverageOptions {
+    /// Build the libtest harness (`rustc --test`) and run the tests instead of `main`.
+    #[serde(default)]
+    pub tests: bool,
+}
+
+impl CoverageOptions {
+    pub fn rustc_args(self) -> Vec<&'static str> {
+        let mut args = vec!["-Cinstrument-coverage"];
+        if self.tests {
+            args.push("--test");
+        }
+        args
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Report {
+    pub file: String,
+    /// Percentage of instrumented lines that ran at least once.
+    pub line_percent: f64,
+    /// Hit count for every instrumented line.
+    pub lines: Vec<LineHits>,
+    pub functions: Vec<FunctionCoverage>,
+    /// The same data in LCOV format, for existing coverage tooling.
+    pub lcov: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LineHits {
+    pub line: u64,
+    pub count: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FunctionCoverage {
+    pub name: String,
+    pub line: u64,
+    /// How many times the function was entered.
+    pub count: u64,
+    pub covered_regions: u64,
+    pub total_regions: u64,
+    pub percent: f64,
+}
+
+// Subset of `llvm-cov export -format=text` output we care about.
+#[derive(Deserialize)]
+struct Export {
+    data: Vec<ExportData>,
+}
+
+#[derive(Deserialize)]
+struct ExportData {
+    #[serde(default)]
+    functions: Vec<ExportFunction>,
+}
+
+#[derive(Deserialize)]
+struct ExportFunction {
+    name: String,
+    count: u64,
+    /// `[line_start, col_start, line_end, col_end, count, file_id, expanded_file_id, kind]`
+    regions: Vec<Vec<u64>>,
+}
+
+// Regions of other kinds (expansions, skipped, gap, branch) don't count
+// towards coverage.
+const CODE_REGION: u64 = 0;
+
+/// Merges the raw profile written by the instrumented binary and builds the
+/// report for `source`.
+pub async fn report(exe: &Path, source: &Path, profraw: &Path, profdata: &Path) -> io::Result<Report> {
+    run(Command::new(llvm_tool("llvm-profdata").await)
+        .args(["merge", "-sparse"])
+        .arg(profraw)
+        .arg("-o")
+        .arg(profdata))
+    .await?;
+
+    let export = |format: &str| {
+        let mut cmd = Command::new(llvm_tool_path("llvm-cov"));
+        cmd.arg("export")
+            .arg(format!("-format={}", format))
+            .arg(format!("-instr-profile={}", profdata.display()))
+            .arg(exe)
+            .arg(source);
+        cmd
+    };
+
+    // Only the submitted file is exported, so every record is about it; don't
+    // leak the server-side temp path.
+    let lcov = run(&mut export("lcov"))
+        .await?
+        .lines()
+        .map(|line| match line.starts_with("SF:") {
+            true => format!("SF:{}\n", DISPLAY_NAME),
+            false => format!("{}\n", line),
+        })
+        .collect::<String>();
+    let json: Export = serde_json::from_str(&run(&mut export("text")).await?)
+        .map_err(|e| io::Error::other(format!("unexpected llvm-cov output: {}", e)))?;
+
+    let lines = parse_lcov_lines(&lcov);
+    let covered = lines.iter().filter(|l| l.count > 0).count();
+    let functions = json
+        .data
+        .into_iter()
+        .flat_map(|d| d.functions)
+        .map(function_coverage)
+        .collect();
+
+    Ok(Report {
+        file: DISPLAY_NAME.to_string(),
+        line_percent: percent(covered as u64, lines.len() as u64),
+        lines,
+        functions,
+        lcov,
+    })
+}
+
+fn parse_lcov_lines(lcov: &str) -> Vec<LineHits> {
+    lcov.lines()
+        .filter_map(|line| line.strip_prefix("DA:"))
+        .filter_map(|rest| {
+            let mut parts = rest.split(',');
+            Some(LineHits {
+                line: parts.next()?.parse().ok()?,
+                count: parts.next()?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+fn function_coverage(function: ExportFunction) -> FunctionCoverage {
+    let code: Vec<_> = function
+        .regions
+        .iter()
+        .filter(|r| r.get(7).copied().unwrap_or(CODE_REGION) == CODE_REGION)
+        .collect();
+    let covered = code.iter().filter(|r| r.get(4).copied().unwrap_or(0) > 0).count() as u64;
+    let total = code.len() as u64;
+
+    FunctionCoverage {
+        name: rustc_demangle::demangle(&function.name).to_string(),
+        line: function.regions.first().and_then(|r| r.first()).copied().unwrap_or(0),
+        count: function.count,
+        covered_regions: covered,
+        total_regions: total,
+        percent: percent(covered, total),
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    (part as f64 * 1000.0 / total as f64).round() / 10.0
+}
+
+async fn run(cmd: &mut Command) -> io::Result<String> {
+    let output = cmd.output().await?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+static TOOLS_DIR: OnceCell<Option<PathBuf>> = OnceCell::const_new();
+
+/// Finds an LLVM tool matching the compiler's LLVM version: the copy from the
+/// `llvm-tools` rustup component if it's installed, otherwise whatever is on
+/// `PATH`.
+async fn llvm_tool(name: &str) -> PathBuf {
+    TOOLS_DIR
+        .get_or_init(|| async {
+            let output = Command::new("rustc").args(["--print", "target-libdir"]).output().await.ok()?;
+            let libdir = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+            let bin = libdir.parent()?.join("bin");
+            bin.join("llvm-profdata").exists().then_some(bin)
+        })
+        .await;
+    llvm_tool_path(name)
+}
+
+fn llvm_tool_path(name: &str) -> PathBuf {
+    match TOOLS_DIR.get().and_then(|dir| dir.as_ref()) {
+        Some(dir) => dir.join(name),
+        None => PathBuf::from(name),
+    }
+}
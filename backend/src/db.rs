@@ -1,9 +1,19 @@
 use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite};
-use std::env;
+use std::{env, sync::Arc};
+use tokio::sync::Semaphore;
+
+use crate::{check, config::Config, ratelimit::RateLimits, session::Sessions};
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Pool<Sqlite>,
+    pub config: Arc<Config>,
+    /// One permit per running rust-analyzer session.
+    pub lsp_sessions: Arc<Semaphore>,
+    pub checks: check::Sessions,
+    /// Runs started over `/ws`.
+    pub sessions: Sessions,
+    pub rate_limits: RateLimits,
 }
 
 pub async fn init_db() -> Pool<Sqlite> {
@@ -19,21 +29,42 @@ pub async fn init_db() -> Pool<Sqlite> {
         .connect(&database_url)
         .await
         .expect("Failed to connect to database");
+    create_tables(&pool).await;
+
+    println!("✅ Database initialized successfully");
+
+    pool
+}
 
+/// Creates the tables that don't exist yet and brings older ones up to date.
+pub async fn create_tables(pool: &Pool<Sqlite>) {
     // Create Users Table
     sqlx::query(
         r#"
         CREATE TABLE IF NOT EXISTS users (
             id TEXT PRIMARY KEY,
             username TEXT UNIQUE NOT NULL,
-            password_hash TEXT NOT NULL
+            password_hash TEXT NOT NULL,
+            role TEXT NOT NULL DEFAULT 'user'
         );
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await
     .expect("Failed to create users table");
 
+    // Users made before roles existed are plain users
+    let has_role: bool = sqlx::query_scalar("SELECT COUNT(*) > 0 FROM pragma_table_info('users') WHERE name = 'role'")
+        .fetch_one(pool)
+        .await
+        .expect("Failed to read users table");
+    if !has_role {
+        sqlx::query("ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user'")
+            .execute(pool)
+            .await
+            .expect("Failed to add role to users table");
+    }
+
     // Create Snippets Table
     sqlx::query(
         r#"
@@ -48,11 +79,50 @@ pub async fn init_db() -> Pool<Sqlite> {
         );
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await
     .expect("Failed to create snippets table");
 
-    println!("✅ Database initialized successfully");
+    // Create Runs Table, the history of signed-in users' runs
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS runs (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            code_hash TEXT NOT NULL,
+            options TEXT NOT NULL,
+            verdict TEXT NOT NULL,
+            exit_code INTEGER,
+            duration_ms INTEGER,
+            total_ms INTEGER NOT NULL,
+            result TEXT,
+            output TEXT NOT NULL,
+            output_truncated BOOLEAN NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        );
+        CREATE INDEX IF NOT EXISTS runs_by_user ON runs (user_id, created_at);
+        "#,
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to create runs table");
 
-    pool
+    // Create Daily Usage Table, what each user used per day for quotas
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS daily_usage (
+            user_id TEXT NOT NULL,
+            day TEXT NOT NULL,
+            cpu_ms INTEGER NOT NULL DEFAULT 0,
+            runs INTEGER NOT NULL DEFAULT 0,
+            artifact_bytes INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (user_id, day),
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        );
+        "#,
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to create daily usage table");
 }
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// A compiler or interpreter diagnostic, flattened from rustc's
+/// `--error-format=json` output into what the editor needs to show it.
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct Diagnostic {
+    pub level: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub spans: Vec<Span>,
+    /// Call stack at the point of the error. Only Miri fills this in.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub backtrace: Vec<Frame>,
+    pub notes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rendered: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+pub struct Span {
+    pub file_name: String,
+    pub line_start: usize,
+    pub line_end: usize,
+    pub column_start: usize,
+    pub column_end: usize,
+    pub is_primary: bool,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct Frame {
+    pub function: String,
+    pub file_name: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+// Shape of a single line of rustc's JSON diagnostic output.
+#[derive(Deserialize)]
+struct RawDiagnostic {
+    message: String,
+    level: String,
+    code: Option<RawCode>,
+    #[serde(default)]
+    spans: Vec<Span>,
+    #[serde(default)]
+    children: Vec<RawDiagnostic>,
+    rendered: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawCode {
+    code: String,
+}
+
+impl Diagnostic {
+    /// Parses one line of `--error-format=json` output. Returns `None` for
+    /// anything that isn't a diagnostic, e.g. regular program output.
+    pub fn from_json_line(line: &str) -> Option<Self> {
+        if !line.trim_start().starts_with('{') {
+            return None;
+        }
+        let raw: RawDiagnostic = serde_json::from_str(line).ok()?;
+        Some(raw.into())
+    }
+}
+
+impl From<RawDiagnostic> for Diagnostic {
+    fn from(raw: RawDiagnostic) -> Self {
+        let mut backtrace = Vec::new();
+        let mut notes = Vec::new();
+
+        for child in raw.children {
+            // Miri reports each stack frame as a note such as "inside `foo`"
+            // pointing at the call site.
+            if let Some(function) = child.message.strip_prefix("inside ") {
+                if let Some(span) = child.spans.iter().find(|s| s.is_primary).or(child.spans.first()) {
+                    backtrace.push(Frame {
+                        function: function.trim_matches('`').to_string(),
+                        file_name: span.file_name.clone(),
+                        line: span.line_start,
+                        column: span.column_start,
+                    });
+                    continue;
+                }
+            }
+            notes.push(format!("{}: {}", child.level, child.message));
+        }
+
+        Self {
+            level: raw.level,
+            message: raw.message,
+            code: raw.code.map(|c| c.code),
+            spans: raw.spans,
+            backtrace,
+            notes,
+            rendered: raw.rendered,
+        }
+    }
+}
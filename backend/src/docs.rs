@@ -1,6 +1,17 @@
 use utoipa::OpenApi;
+use crate::artifacts;
 use crate::auth;
+use crate::build;
+use crate::check;
+use crate::diagnostics;
+use crate::format;
+use crate::gc;
+use crate::health;
+use crate::quota;
+use crate::ratelimit;
+use crate::history;
 use crate::snippets;
+use crate::spectate;
 
 #[derive(OpenApi)]
 #[openapi(
@@ -13,6 +24,18 @@ use crate::snippets;
         snippets::update_snippet,
         snippets::patch_snippet,
         snippets::delete_snippet,
+        artifacts::download,
+        artifacts::download_file,
+        check::check_handler,
+        format::format_handler,
+        build::targets_handler,
+        health::health_handler,
+        history::list_runs,
+        history::get_run,
+        quota::usage_handler,
+        spectate::share_handler,
+        spectate::revoke_handler,
+        spectate::spectate_handler,
     ),
     components(
         schemas(
@@ -24,11 +47,34 @@ use crate::snippets;
             snippets::CreateSnippetResponse,
             snippets::UpdateSnippetRequest,
             snippets::PatchSnippetRequest,
+            check::CheckRequest,
+            check::CheckResponse,
+            format::FormatRequest,
+            format::FormatResponse,
+            build::TargetsResponse,
+            diagnostics::Diagnostic,
+            diagnostics::Span,
+            diagnostics::Frame,
+            health::HealthResponse,
+            gc::TempUsage,
+            ratelimit::RateLimitStats,
+            ratelimit::LimiterStats,
+            history::RunSummary,
+            history::RunDetails,
+            quota::UsageResponse,
+            quota::Meter,
+            spectate::SpectateLink,
         )
     ),
     tags(
         (name = "auth", description = "Authentication endpoints"),
-        (name = "snippets", description = "Snippet management endpoints")
+        (name = "snippets", description = "Snippet management endpoints"),
+        (name = "compiler", description = "Compiling and checking code"),
+        (name = "artifacts", description = "Build outputs of finished runs"),
+        (name = "health", description = "Server status"),
+        (name = "history", description = "Runs of the signed-in user"),
+        (name = "usage", description = "Consumption and quotas of the signed-in user"),
+        (name = "sessions", description = "Sharing runs with spectators")
     ),
     modifiers(&SecurityAddon)
 )]
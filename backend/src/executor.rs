@@ -0,0 +1,1279 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{io, path::{Path, PathBuf}, process::Stdio, sync::Arc, time::{Duration, Instant}};
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::{
+    artifacts,
+    build::{self, BuildOptions},
+    cgroup::{self, Group},
+    config::Config,
+    coverage::{self, CoverageOptions},
+    fuzz::{self, FuzzOptions},
+    gc,
+    diagnostics::Diagnostic,
+    files::{self, InputFile},
+    miri,
+    netns::{self, Namespace, Network},
+    output::{Limiter, Truncation},
+    pty::{self, Pty, TerminalSize},
+    quota::Account,
+    sanitizer::{self, SanitizerKind},
+    stall,
+    uids::{self, Lease},
+    usage::{self, Exit, Reaper, Usage},
+    utf8,
+    valgrind::{self, ValgrindTool},
+    wasm,
+};
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    pub code: String,
+    #[serde(default)]
+    pub mode: RunMode,
+    /// Run the program on a pseudo-terminal of this size instead of pipes.
+    #[serde(default)]
+    pub terminal: Option<TerminalSize>,
+    #[serde(default)]
+    pub output: OutputEncoding,
+    #[serde(default)]
+    pub network: Network,
+    /// Written to the program's working directory before it starts.
+    #[serde(default)]
+    pub files: Vec<InputFile>,
+    /// Target triple to build for. Only for builds.
+    #[serde(default)]
+    pub target: Option<String>,
+}
+
+impl CodeRequest {
+    pub fn from_code(code: String) -> Self {
+        Self {
+            code,
+            mode: RunMode::default(),
+            terminal: None,
+            output: OutputEncoding::default(),
+            network: Network::default(),
+            files: Vec::new(),
+            target: None,
+        }
+    }
+}
+
+/// How a client wants the program's stdout and stderr. Terminal runs always
+/// get raw `Terminal` bytes.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputEncoding {
+    /// `stdout`/`stderr` events carrying text. Characters are never split
+    /// between events; invalid UTF-8 is replaced with U+FFFD.
+    #[default]
+    Text,
+    /// `output` events carrying the exact bytes, base64 encoded.
+    Base64,
+    /// The exact bytes as binary WebSocket frames. Same as `base64` over SSE.
+    Binary,
+}
+
+/// Which of the program's output streams something was written to.
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn text(self, data: String) -> RunEvent {
+        match self {
+            Stream::Stdout => RunEvent::Stdout { data },
+            Stream::Stderr => RunEvent::Stderr { data },
+        }
+    }
+}
+
+/// What a client sends to a running program.
+#[derive(Debug)]
+pub enum Input {
+    Data(Vec<u8>),
+    /// Close stdin so the program sees end-of-file. On a terminal this is ^D.
+    Eof,
+    /// The client's terminal changed size. Only meaningful for terminal runs.
+    Resize(TerminalSize),
+}
+
+/// Control messages a WebSocket client sends as JSON text frames while its
+/// program runs. Binary frames are raw stdin data.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Text for stdin, passed through exactly (no newline is added).
+    Input { data: String },
+    /// Bytes for stdin, base64 encoded, for clients that can't send binary frames.
+    Binary { data: String },
+    Eof,
+    Resize(TerminalSize),
+}
+
+impl ClientMessage {
+    pub fn into_input(self) -> Result<Input, String> {
+        match self {
+            ClientMessage::Input { data } => Ok(Input::Data(data.into_bytes())),
+            ClientMessage::Binary { data } => STANDARD
+                .decode(data)
+                .map(Input::Data)
+                .map_err(|e| format!("Invalid base64 input: {}", e)),
+            ClientMessage::Eof => Ok(Input::Eof),
+            ClientMessage::Resize(size) => Ok(Input::Resize(size)),
+        }
+    }
+}
+
+/// Largest piece of input queued at once; bigger messages are split.
+const STDIN_CHUNK: usize = 64 * 1024;
+
+/// Creates the channel carrying a program's input. At most `limit` bytes
+/// may be queued and not yet taken by the program; beyond that, sending waits,
+/// which pushes back on the client (or the upload) instead of buffering it.
+pub fn stdin_channel(limit: usize) -> (StdinSender, StdinReceiver) {
+    let (tx, rx) = mpsc::channel(64);
+    let budget = Arc::new(Semaphore::new(limit.max(STDIN_CHUNK)));
+    (StdinSender { tx, budget: budget.clone() }, StdinReceiver { rx, budget })
+}
+
+#[derive(Clone)]
+pub struct StdinSender {
+    tx: mpsc::Sender<Input>,
+    budget: Arc<Semaphore>,
+}
+
+impl StdinSender {
+    /// Queues `input`, waiting while the buffer is full. Fails once the
+    /// program has stopped taking input.
+    pub async fn send(&self, input: Input) -> Result<(), Closed> {
+        let Input::Data(data) = input else {
+            return self.tx.send(input).await.map_err(|_| Closed);
+        };
+        for chunk in data.chunks(STDIN_CHUNK) {
+            let permits = self.budget.acquire_many(chunk.len() as u32).await.map_err(|_| Closed)?;
+            permits.forget();
+            self.tx.send(Input::Data(chunk.to_vec())).await.map_err(|_| Closed)?;
+        }
+        Ok(())
+    }
+}
+
+/// The program is no longer reading its input.
+#[derive(Debug)]
+pub struct Closed;
+
+pub struct StdinReceiver {
+    rx: mpsc::Receiver<Input>,
+    budget: Arc<Semaphore>,
+}
+
+impl StdinReceiver {
+    async fn recv(&mut self) -> Option<Input> {
+        let input = self.rx.recv().await?;
+        if let Input::Data(data) = &input {
+            self.budget.add_permits(data.len());
+        }
+        Some(input)
+    }
+}
+
+impl Drop for StdinReceiver {
+    /// Senders waiting for room in the buffer get `Closed`, as there won't
+    /// be any.
+    fn drop(&mut self) {
+        self.budget.close();
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunMode {
+    /// Compile with `rustc` and run the binary.
+    #[default]
+    Native,
+    /// Interpret the program under Miri to catch undefined behavior.
+    Miri,
+    /// Build with a sanitizer on nightly and run the instrumented binary.
+    Sanitizer(SanitizerKind),
+    /// Run the binary under valgrind's memcheck or callgrind.
+    Valgrind(ValgrindTool),
+    /// Build with `-C instrument-coverage`, run the program (or its tests)
+    /// and report which lines ran.
+    Coverage(CoverageOptions),
+    /// Build a `fuzz_target!` with libFuzzer and fuzz it for a bounded time.
+    Fuzz(FuzzOptions),
+    /// Compile for `wasm32-wasip1` and run the module in the embedded WASI
+    /// runtime instead of as a native process.
+    Wasm,
+    /// Compile without running and keep the output for download.
+    Build(BuildOptions),
+}
+
+/// Everything a run reports back to the client, in order.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RunEvent {
+    Stdout { data: String },
+    Stderr { data: String },
+    /// Program output as written, for clients that asked for raw bytes.
+    /// Base64 in JSON.
+    Output {
+        stream: Stream,
+        #[serde(serialize_with = "base64")]
+        data: Vec<u8>,
+    },
+    CompileError { data: String },
+    Diagnostic(Diagnostic),
+    FuzzProgress(fuzz::Progress),
+    /// Raw output of a terminal run, stdout and stderr interleaved as the
+    /// program wrote them, escape sequences included. Base64 in JSON.
+    Terminal {
+        #[serde(serialize_with = "base64")]
+        data: Vec<u8>,
+    },
+    /// The program's output went over a limit and was cut short.
+    OutputLimit(Truncation),
+    Error { message: String },
+    /// Input a `/ws` client sent, as text, once the program's stdin took it.
+    /// `eof` when the client closed stdin. Not echoed for terminal runs, whose
+    /// terminal does that itself.
+    Stdin { data: String, eof: bool },
+    Exit(Box<RunResult>),
+    /// Sent by `/ws` when a client attaches to a run, before the run's own
+    /// events, and again if events had to be skipped. Not one of the run's
+    /// events and not counted in offsets. Spectators don't get the id, which
+    /// gives control of the run.
+    Session {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+        offset: u64,
+        missed: u64,
+    },
+}
+
+impl RunEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RunEvent::Stdout { .. } => "stdout",
+            RunEvent::Stderr { .. } => "stderr",
+            RunEvent::Output { .. } => "output",
+            RunEvent::CompileError { .. } => "compile_error",
+            RunEvent::Diagnostic(_) => "diagnostic",
+            RunEvent::FuzzProgress(_) => "fuzz_progress",
+            RunEvent::Terminal { .. } => "terminal",
+            RunEvent::OutputLimit(_) => "output_limit",
+            RunEvent::Error { .. } => "error",
+            RunEvent::Stdin { .. } => "stdin",
+            RunEvent::Exit(_) => "exit",
+            RunEvent::Session { .. } => "session",
+        }
+    }
+}
+
+fn base64<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(bytes))
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RunResult {
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    /// The program was stopped for waiting on stdin longer than
+    /// `STDIN_TIMEOUT_SECS`.
+    pub stdin_timed_out: bool,
+    pub duration_ms: u64,
+    /// Why a wasm guest was stopped by the runtime, e.g. the memory cap.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trap: Option<String>,
+    /// Where the build output (the `.wasm` module, or what a build produced)
+    /// can be downloaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_url: Option<String>,
+    /// Size of the build output, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_size: Option<u64>,
+    /// When `artifact_url` stops working.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_expires_at: Option<DateTime<Utc>>,
+    pub usage: ResourceUsage,
+    /// The program's network namespace, when runs are isolated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<netns::Report>,
+    /// Files the program wrote in its working directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_files: Option<files::OutputFiles>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sanitizer_findings: Vec<sanitizer::Finding>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valgrind: Option<valgrind::Report>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<coverage::Report>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fuzz: Option<fuzz::Report>,
+}
+
+/// What the compiler and the program used. Left out for steps that don't
+/// run as a process of their own (Miri has no compile step, the wasm guest
+/// runs inside the server) and on non-Unix hosts.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ResourceUsage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compile: Option<Usage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program: Option<Usage>,
+    /// The run's cgroup, when the server has a cgroup v2 subtree to use.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cgroup: Option<cgroup::Accounting>,
+}
+
+/// Buffer size of the in-memory pipes connecting a wasm guest's stdio.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Where a run's output goes: the client's events, within the run's output
+/// limits, as text or (with `raw`) as `Output` bytes.
+#[derive(Clone)]
+struct Sink {
+    events: mpsc::Sender<RunEvent>,
+    limiter: Limiter,
+    raw: bool,
+}
+
+/// How long `pump_lines` waits for the rest of a line before forwarding the
+/// part it has.
+const PARTIAL_LINE_DELAY: Duration = Duration::from_millis(100);
+
+/// Most stderr we keep around for post-run analysis such as sanitizer reports.
+const STDERR_CAPTURE_LIMIT: usize = 1024 * 1024;
+
+/// Name of the source file in a run's workspace.
+const SOURCE_NAME: &str = "main.rs";
+
+/// A single run's own directory inside the temp directory, `run_<uuid>`.
+/// The source, the binary and tool output sit at its top; the program runs
+/// in `files/`, which holds its input files and whatever it writes. Paths
+/// are absolute, since the program doesn't run in the server's directory.
+/// With run users on, the run has a user of its own, the only one that can
+/// get into the directory besides the server.
+pub(crate) struct Workspace {
+    dir: PathBuf,
+    pub source: PathBuf,
+    pub exe: PathBuf,
+    /// The program's working directory.
+    pub files: PathBuf,
+    user: Option<Lease>,
+    _hold: gc::Hold,
+}
+
+impl Workspace {
+    pub async fn create(config: &Config) -> io::Result<Self> {
+        gc::admit(config).await?;
+        let user = config.run_uids.as_ref().map(Lease::take).transpose()?;
+        let dir = std::path::absolute(config.temp_dir.join(format!("run_{}", Uuid::new_v4())))?;
+        let hold = gc::Hold::new(&dir);
+        let files = dir.join("files");
+        fs::create_dir_all(&files).await?;
+
+        let exe = if cfg!(target_os = "windows") { "main.exe" } else { "main" };
+        Ok(Self { source: dir.join(SOURCE_NAME), exe: dir.join(exe), files, dir, user, _hold: hold })
+    }
+
+    /// Gives the directory, and what was put in it so far, to the run's user.
+    pub async fn hand_over(&self) -> io::Result<()> {
+        let Some(user) = &self.user else { return Ok(()) };
+        let (dir, uid) = (self.dir.clone(), user.uid());
+        tokio::task::spawn_blocking(move || uids::hand_over(&dir, uid)).await.map_err(io::Error::other)?
+    }
+
+    /// How a program of this run is confined.
+    fn confinement(&self, network: Network) -> Confinement {
+        Confinement { network, uid: self.user.as_ref().map(Lease::uid) }
+    }
+
+    /// Puts `compiler`, which reads the run's source, in a cgroup and network
+    /// namespace of its own and makes it run as the run's user, like the
+    /// program: macros like `include_str!` read whatever it can.
+    pub async fn confine_compiler(&self, config: &Config, compiler: &mut Command) -> Result<Enclosure, String> {
+        Enclosure::enter(config, compiler, self.confinement(Network::None)).await
+    }
+
+    /// Path for an auxiliary file of this run, e.g. a tool's log or profile.
+    pub fn file(&self, extension: &str) -> PathBuf {
+        self.dir.join(format!("main.{}", extension))
+    }
+
+    /// Removes the directory and everything in it.
+    pub async fn cleanup(&self) {
+        let _ = fs::remove_dir_all(&self.dir).await;
+    }
+}
+
+/// Compiles and runs `request`, reporting progress on `events` and ending with
+/// an `Exit` event unless something failed before the program started.
+/// `stdin` carries input for the program; without it stdin is `/dev/null`.
+/// The program is killed as soon as the receiving side of `events` goes away.
+/// What rustc and the program used is charged to `account`, whether or not
+/// the client stayed to the end.
+pub async fn execute(
+    config: Arc<Config>,
+    request: CodeRequest,
+    events: mpsc::Sender<RunEvent>,
+    stdin: Option<StdinReceiver>,
+    account: Option<Account>,
+) {
+    if request.terminal.is_some() && request.mode != RunMode::Native {
+        let message = "Terminal mode is only available for native runs".to_string();
+        let _ = events.send(RunEvent::Error { message }).await;
+        return;
+    }
+    if request.target.is_some() && !matches!(request.mode, RunMode::Build(_)) {
+        let message = "A target can only be chosen for builds".to_string();
+        let _ = events.send(RunEvent::Error { message }).await;
+        return;
+    }
+
+    let workspace = match Workspace::create(&config).await {
+        Ok(w) => w,
+        Err(e) => {
+            let _ = events.send(RunEvent::Error { message: format!("Failed to create temp dir: {}", e) }).await;
+            return;
+        }
+    };
+
+    let source = match request.mode {
+        RunMode::Fuzz(_) => fuzz::harness(&request.code),
+        _ => request.code,
+    };
+    if let Err(e) = fs::write(&workspace.source, source).await {
+        let _ = events.send(RunEvent::Error { message: format!("Failed to write file: {}", e) }).await;
+        workspace.cleanup().await;
+        return;
+    }
+    let snapshot = match files::provision(&workspace.files, &request.files).await {
+        Ok(snapshot) => snapshot,
+        Err(message) => {
+            let _ = events.send(RunEvent::Error { message }).await;
+            workspace.cleanup().await;
+            return;
+        }
+    };
+    if let Err(e) = workspace.hand_over().await {
+        let _ = events.send(RunEvent::Error { message: format!("Failed to set up run user: {}", e) }).await;
+        workspace.cleanup().await;
+        return;
+    }
+
+    let sink = Sink {
+        events: events.clone(),
+        limiter: Limiter::new(config.output_limits),
+        raw: request.output != OutputEncoding::Text,
+    };
+    let mut compile_usage = None;
+    let outcome = match request.mode {
+        RunMode::Wasm => match compile_to(&config, wasm_rustc(), &workspace, &workspace.file("wasm"), &mut compile_usage).await {
+            Ok(module) => supervise_wasm(&config, &module, &workspace.files, &sink, stdin).await,
+            Err(event) => Err(event),
+        },
+        RunMode::Build(options) => {
+            build(&config, options, request.target.as_deref(), &workspace, &mut compile_usage)
+                .await
+                .map(|result| (result, Vec::new()))
+        }
+        mode => match prepare(&config, mode, &workspace, &mut compile_usage).await {
+            Ok(mut command) => {
+                command.current_dir(&workspace.files);
+                let confinement = workspace.confinement(request.network);
+                supervise(&config, command, mode, request.terminal, confinement, &sink, stdin).await
+            }
+            Err(event) => Err(event),
+        },
+    };
+
+    // What the run used, for its account: the result, and those of any
+    // programs run after it
+    let mut spent = Vec::new();
+    match outcome {
+        // Nobody is left to report to, and nothing more to store
+        Ok((mut result, _)) if events.is_closed() => {
+            result.usage.compile = compile_usage;
+            spent.push(result);
+        }
+        Ok((mut result, stderr)) => {
+            result.usage.compile = compile_usage;
+            if let Some(truncation) = sink.limiter.truncation() {
+                let _ = events.send(RunEvent::OutputLimit(truncation)).await;
+            }
+            match files::collect(&config, &workspace.files, &snapshot).await {
+                Ok(collected) if !collected.files.is_empty() || collected.omitted > 0 => {
+                    result.output_files = Some(collected);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    let message = format!("Failed to collect output files: {}", e);
+                    let _ = events.send(RunEvent::Error { message }).await;
+                }
+            }
+            analyze(&config, request.mode, &workspace, &stderr, &mut result, &events).await;
+            if let (RunMode::Fuzz(options), Some(report)) = (request.mode, &mut result.fuzz) {
+                minimize(&config, options, request.network, &workspace, &events, report, &mut spent).await;
+            }
+            spent.push(result.clone());
+            let _ = events.send(RunEvent::Exit(Box::new(result))).await;
+        }
+        Err(event) => {
+            let usage = ResourceUsage { compile: compile_usage, ..Default::default() };
+            spent.push(RunResult { usage, ..Default::default() });
+            let _ = events.send(event).await;
+        }
+    }
+
+    workspace.cleanup().await;
+    if let Some(account) = account {
+        if let Err(e) = account.charge(request.mode, &spent).await {
+            eprintln!("Failed to charge run: {}", e);
+        }
+    }
+}
+
+/// Does whatever has to happen before the program can start and returns the
+/// command that runs it. What compiling it used goes in `compile_usage`.
+async fn prepare(
+    config: &Config,
+    mode: RunMode,
+    workspace: &Workspace,
+    compile_usage: &mut Option<Usage>,
+) -> Result<Command, RunEvent> {
+    match mode {
+        RunMode::Native => Ok(Command::new(compile(config, Command::new("rustc"), workspace, compile_usage).await?)),
+        RunMode::Miri => miri::command(config, &workspace.source)
+            .await
+            .map_err(|e| RunEvent::Error { message: format!("Failed to set up Miri: {}", e) }),
+        RunMode::Sanitizer(kind) => {
+            let mut rustc = nightly(config, "rustc");
+            rustc.args(kind.rustc_args());
+            let mut command = Command::new(compile(config, rustc, workspace, compile_usage).await?);
+            let (key, value) = kind.env();
+            command.env(key, value);
+            Ok(command)
+        }
+        RunMode::Valgrind(tool) => {
+            let mut rustc = Command::new("rustc");
+            rustc.arg("-Cdebuginfo=2");
+            let exe = compile(config, rustc, workspace, compile_usage).await?;
+            Ok(tool.command(&exe, &workspace.file("log"), &workspace.file("data")))
+        }
+        RunMode::Coverage(options) => {
+            let mut rustc = Command::new("rustc");
+            rustc.args(options.rustc_args());
+            let mut command = Command::new(compile(config, rustc, workspace, compile_usage).await?);
+            command.env("LLVM_PROFILE_FILE", workspace.file("profraw"));
+            Ok(command)
+        }
+        RunMode::Fuzz(options) => {
+            let Some(libfuzzer) = &config.libfuzzer_path else {
+                return Err(RunEvent::Error { message: "Fuzzing is not available on this server".to_string() });
+            };
+            let mut rustc = nightly(config, "rustc");
+            rustc.args(fuzz::rustc_args(libfuzzer));
+            let mut command = Command::new(compile(config, rustc, workspace, compile_usage).await?);
+            command.args(fuzz::session_args(options.duration(config), &workspace.file(fuzz::ARTIFACT_PREFIX)));
+            Ok(command)
+        }
+        RunMode::Wasm => unreachable!("wasm modules run in-process, not as a command"),
+        RunMode::Build(_) => unreachable!("builds don't run anything"),
+    }
+}
+
+/// Shrinks the crashing inputs of a fuzzing session by running the target
+/// again in `-minimize_crash` mode, supervised like the session itself. What
+/// it prints isn't sent to the client; what it uses is added to `spent`.
+async fn minimize(
+    config: &Config,
+    options: FuzzOptions,
+    network: Network,
+    workspace: &Workspace,
+    events: &mpsc::Sender<RunEvent>,
+    report: &mut fuzz::Report,
+    spent: &mut Vec<RunResult>,
+) {
+    let duration = options.duration(config);
+    let output = workspace.file("minimized");
+    for crash in &mut report.crashes {
+        let _ = fs::remove_file(&output).await;
+        let mut command = Command::new(&workspace.exe);
+        command.args(fuzz::minimize_args(&crash.path, &output, duration)).current_dir(&workspace.files);
+
+        // Output is thrown away, until the client goes away and the target is
+        // stopped like any program whose client left.
+        let (quiet, mut discarded) = mpsc::channel(64);
+        let client = events.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    event = discarded.recv() => if event.is_none() { break },
+                    _ = client.closed() => break,
+                }
+            }
+        });
+        let sink = Sink { events: quiet, limiter: Limiter::new(config.output_limits), raw: false };
+        let mode = RunMode::Fuzz(fuzz::minimize_options(duration));
+        let confinement = workspace.confinement(network);
+        let Ok((result, _)) = supervise(config, command, mode, None, confinement, &sink, None).await else {
+            return;
+        };
+        spent.push(result);
+        if events.is_closed() {
+            return;
+        }
+        crash.minimized = fuzz::read_artifact(&output)
+            .await
+            .filter(|minimized| minimized.len() < crash.size)
+            .map(|minimized| STANDARD.encode(minimized));
+    }
+}
+
+/// Wall-clock limit for the program. A fuzzing session runs for as long as
+/// it was asked to, plus a margin for libFuzzer to wrap up.
+fn run_timeout(config: &Config, mode: RunMode) -> Duration {
+    match mode {
+        RunMode::Fuzz(options) => options.duration(config) + Duration::from_secs(10),
+        _ => config.run_timeout,
+    }
+}
+
+/// Fills in the mode-specific parts of `result` once the program has exited.
+/// Problems building a report are sent as `Error` events; the run itself
+/// still completes.
+async fn analyze(
+    config: &Config,
+    mode: RunMode,
+    workspace: &Workspace,
+    stderr: &[u8],
+    result: &mut RunResult,
+    events: &mpsc::Sender<RunEvent>,
+) {
+    match mode {
+        RunMode::Sanitizer(_) => {
+            result.sanitizer_findings = sanitizer::parse_reports(&String::from_utf8_lossy(stderr));
+        }
+        RunMode::Valgrind(tool) => {
+            let (path, parse): (_, fn(&str) -> valgrind::Report) = match tool {
+                ValgrindTool::Memcheck => (workspace.file("log"), valgrind::parse_memcheck),
+                ValgrindTool::Callgrind => (workspace.file("data"), valgrind::parse_callgrind),
+            };
+            if let Ok(text) = fs::read_to_string(path).await {
+                result.valgrind = Some(parse(&text));
+            }
+        }
+        RunMode::Coverage(_) => {
+            let report = coverage::report(
+                &workspace.exe,
+                &workspace.source,
+                &workspace.file("profraw"),
+                &workspace.file("profdata"),
+            )
+            .await;
+            match report {
+                Ok(report) => result.coverage = Some(report),
+                Err(e) => {
+                    let message = format!("Failed to build coverage report: {}", e);
+                    let _ = events.send(RunEvent::Error { message }).await;
+                }
+            }
+        }
+        RunMode::Fuzz(_) => {
+            let stderr = String::from_utf8_lossy(stderr);
+            result.fuzz = Some(fuzz::report(&workspace.file(fuzz::ARTIFACT_PREFIX), &stderr).await);
+        }
+        RunMode::Wasm => match artifacts::store(config, &workspace.file("wasm"), "wasm").await {
+            Ok((url, size)) => {
+                result.artifact_url = Some(url);
+                result.artifact_size = Some(size);
+                result.artifact_expires_at = Some(artifacts::expires_at(config));
+            }
+            Err(e) => {
+                let message = format!("Failed to store wasm module: {}", e);
+                let _ = events.send(RunEvent::Error { message }).await;
+            }
+        },
+        RunMode::Native | RunMode::Miri | RunMode::Build(_) => {}
+    }
+}
+
+/// Compiles for `target` (the host by default) and stores the output. The
+/// result's `exit_code` is rustc's.
+async fn build(
+    config: &Config,
+    options: BuildOptions,
+    target: Option<&str>,
+    workspace: &Workspace,
+    compile_usage: &mut Option<Usage>,
+) -> Result<RunResult, RunEvent> {
+    if let Some(target) = target {
+        let installed = build::installed_targets()
+            .await
+            .map_err(|e| RunEvent::Error { message: format!("Failed to list targets: {}", e) })?;
+        if !installed.iter().any(|t| t == target) {
+            return Err(RunEvent::Error { message: format!("Target {} is not installed", target) });
+        }
+    }
+    let (args, kind) = options.rustc_args(target).map_err(|message| RunEvent::Error { message })?;
+
+    let started = Instant::now();
+    let mut rustc = Command::new("rustc");
+    rustc.args(args);
+    let output = compile_to(config, rustc, workspace, &workspace.file(kind), compile_usage).await?;
+    let duration_ms = started.elapsed().as_millis() as u64;
+
+    let (url, size) = artifacts::store(config, &output, kind)
+        .await
+        .map_err(|e| RunEvent::Error { message: format!("Failed to store build output: {}", e) })?;
+    let result = RunResult {
+        exit_code: Some(0),
+        duration_ms,
+        artifact_url: Some(url),
+        artifact_size: Some(size),
+        artifact_expires_at: Some(artifacts::expires_at(config)),
+        ..Default::default()
+    };
+    Ok(result)
+}
+
+/// Runs `tool` from the configured nightly toolchain.
+pub fn nightly(config: &Config, tool: &str) -> Command {
+    let mut command = Command::new("rustup");
+    command.args(["run", &config.nightly_toolchain, tool]);
+    command
+}
+
+fn wasm_rustc() -> Command {
+    let mut rustc = Command::new("rustc");
+    rustc.args(["--target", wasm::TARGET]);
+    rustc
+}
+
+/// Compiles the workspace source with `rustc` and returns the absolute path
+/// of the resulting binary. What rustc used goes in `usage`, whether or not
+/// it succeeded.
+async fn compile(
+    config: &Config,
+    rustc: Command,
+    workspace: &Workspace,
+    usage: &mut Option<Usage>,
+) -> Result<PathBuf, RunEvent> {
+    compile_to(config, rustc, workspace, &workspace.exe, usage).await
+}
+
+/// Like `compile`, for builds whose output isn't the workspace binary.
+async fn compile_to(
+    config: &Config,
+    mut rustc: Command,
+    workspace: &Workspace,
+    output: &Path,
+    usage: &mut Option<Usage>,
+) -> Result<PathBuf, RunEvent> {
+    // Relative, so messages and panics say `main.rs` rather than where the
+    // server keeps it.
+    rustc.current_dir(&workspace.dir).arg(SOURCE_NAME).arg("-o").arg(output);
+    let enclosure = workspace
+        .confine_compiler(config, &mut rustc)
+        .await
+        .map_err(|message| RunEvent::Error { message })?;
+    let outcome = usage::output(&mut rustc).await;
+    enclosure.finish().await;
+    let (result, used) = outcome.map_err(|e| RunEvent::Error { message: format!("Failed to execute rustc: {}", e) })?;
+    *usage = used;
+
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr).to_string();
+        return Err(RunEvent::CompileError { data: stderr });
+    }
+
+    std::path::absolute(output).map_err(|e| RunEvent::Error { message: format!("Failed to resolve binary: {}", e) })
+}
+
+/// Runs the program to completion, streaming its output. Returns the result
+/// along with the captured stderr (only kept for modes that analyze it),
+/// also when the client went away before it finished and it was stopped. With a cgroup v2
+/// subtree configured, the program and everything it starts run in a cgroup
+/// of their own that is torn down afterwards. With network isolation on,
+/// they also get a network namespace of their own, and with a run user, they
+/// run as that user.
+async fn supervise(
+    config: &Config,
+    mut command: Command,
+    mode: RunMode,
+    terminal: Option<TerminalSize>,
+    confinement: Confinement,
+    sink: &Sink,
+    stdin: Option<StdinReceiver>,
+) -> Result<(RunResult, Vec<u8>), RunEvent> {
+    usage::own_memory(&mut command);
+    let enclosure = Enclosure::enter(config, &mut command, confinement)
+        .await
+        .map_err(|message| RunEvent::Error { message })?;
+
+    let limits = Limits {
+        run: run_timeout(config, mode),
+        stdin: config.stdin_timeout.filter(|_| stdin.is_some()),
+    };
+    let group = enclosure.group.as_ref();
+    let mut outcome = match terminal {
+        Some(size) => supervise_terminal(command, size, limits, sink, stdin, group).await,
+        None => supervise_pipes(command, mode, limits, sink, stdin, group).await,
+    };
+    let (accounting, network) = enclosure.finish().await;
+    if let Ok((result, _)) = &mut outcome {
+        result.usage.cgroup = accounting;
+        result.network = network;
+    }
+    outcome
+}
+
+/// What a program is kept from besides its limits.
+#[derive(Clone, Copy)]
+struct Confinement {
+    network: Network,
+    /// The run's user, unless the program runs as the server's.
+    uid: Option<u32>,
+}
+
+/// The cgroup and network namespace a process of a run is started in, when
+/// the server has them on.
+pub(crate) struct Enclosure {
+    group: Option<Group>,
+    namespace: Option<Namespace>,
+}
+
+impl Enclosure {
+    /// Creates them and makes `command` start inside, as the run's user if
+    /// there is one.
+    async fn enter(config: &Config, command: &mut Command, confinement: Confinement) -> Result<Self, String> {
+        let group = match &config.cgroup_root {
+            Some(root) => Some(
+                Group::create(root, &config.cgroup_limits)
+                    .await
+                    .map_err(|e| format!("Failed to create cgroup: {}", e))?,
+            ),
+            None => None,
+        };
+        if let Some(group) = &group {
+            group.join(command);
+        }
+        let namespace = if config.network_isolation {
+            let namespace = match Namespace::create(confinement.network).await {
+                Ok(namespace) => namespace,
+                Err(e) => {
+                    if let Some(group) = group {
+                        group.finish().await;
+                    }
+                    return Err(format!("Failed to create network namespace: {}", e));
+                }
+            };
+            namespace.join(command);
+            Some(namespace)
+        } else {
+            None
+        };
+        // Last, since joining the others takes root
+        if let Some(uid) = confinement.uid {
+            uids::run_as(command, uid);
+        }
+        Ok(Self { group, namespace })
+    }
+
+    /// Tears the cgroup down, killing whatever is left in it, and returns
+    /// what it and the namespace accounted for.
+    pub async fn finish(self) -> (Option<cgroup::Accounting>, Option<netns::Report>) {
+        let accounting = match self.group {
+            Some(group) => Some(group.finish().await),
+            None => None,
+        };
+        (accounting, self.namespace.as_ref().map(Namespace::report))
+    }
+}
+
+/// Time limits `wait` holds a program to.
+#[derive(Clone, Copy)]
+struct Limits {
+    run: Duration,
+    /// For waiting on stdin, when the program has input to wait for.
+    stdin: Option<Duration>,
+}
+
+/// Why `wait` stopped a program.
+#[derive(Clone, Copy, PartialEq)]
+enum Stop {
+    Timeout,
+    StdinTimeout,
+    OutputLimit,
+    /// The client went away.
+    Abandoned,
+}
+
+/// `supervise` for programs on pipes.
+async fn supervise_pipes(
+    mut command: Command,
+    mode: RunMode,
+    limits: Limits,
+    sink: &Sink,
+    stdin: Option<StdinReceiver>,
+    group: Option<&Group>,
+) -> Result<(RunResult, Vec<u8>), RunEvent> {
+    command
+        .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let (mut reaper, pipes) = usage::spawn(&mut command)
+        .map_err(|e| RunEvent::Error { message: format!("Failed to spawn process: {}", e) })?;
+    let started = Instant::now();
+
+    let stdout = pipes.stdout.expect("child did not have a handle to stdout");
+    let stderr = pipes.stderr.expect("child did not have a handle to stderr");
+
+    let capture_stderr = matches!(mode, RunMode::Sanitizer(_) | RunMode::Fuzz(_));
+    let stdout_task = tokio::spawn(pump(stdout, sink.clone(), Stream::Stdout, false));
+    let stderr_task = match mode {
+        RunMode::Miri => tokio::spawn(pump_lines(stderr, sink.clone(), diagnostic_or_stderr, false)),
+        RunMode::Fuzz(_) => tokio::spawn(pump_lines(stderr, sink.clone(), fuzz_progress_or_stderr, true)),
+        _ => tokio::spawn(pump(stderr, sink.clone(), Stream::Stderr, capture_stderr)),
+    };
+    let stdin_task = match (pipes.stdin, stdin) {
+        (Some(pipe), Some(input)) => Some(tokio::spawn(feed(pipe, input))),
+        _ => None,
+    };
+
+    let (exit, stop) = wait(&mut reaper, limits, sink, group).await;
+    let duration_ms = started.elapsed().as_millis() as u64;
+    if let Some(task) = stdin_task {
+        task.abort();
+    }
+
+    // Give the readers a moment to forward what is still buffered in the pipes.
+    // Anything the program leaked (e.g. a background grandchild) can hold them
+    // open, so don't wait forever. A cgroup takes those down with it.
+    if let Some(group) = group {
+        group.kill().await;
+    }
+    drain(stdout_task).await;
+    let stderr = drain(stderr_task).await;
+
+    let result = RunResult {
+        exit_code: exit.as_ref().and_then(|exit| exit.status.code()),
+        timed_out: stop == Some(Stop::Timeout),
+        stdin_timed_out: stop == Some(Stop::StdinTimeout),
+        duration_ms,
+        usage: ResourceUsage { program: exit.and_then(|exit| exit.usage), ..Default::default() },
+        ..Default::default()
+    };
+    Ok((result, stderr))
+}
+
+/// `supervise` for terminal runs: the program gets a pseudo-terminal for its
+/// stdio and everything it prints is forwarded as raw `Terminal` bytes.
+async fn supervise_terminal(
+    mut command: Command,
+    size: TerminalSize,
+    limits: Limits,
+    sink: &Sink,
+    stdin: Option<StdinReceiver>,
+    group: Option<&Group>,
+) -> Result<(RunResult, Vec<u8>), RunEvent> {
+    let terminal_error = |e: io::Error| RunEvent::Error { message: format!("Failed to open terminal: {}", e) };
+    let (mut reaper, pty) = pty::spawn(&mut command, size)
+        .map_err(|e| RunEvent::Error { message: format!("Failed to spawn process: {}", e) })?;
+    let started = Instant::now();
+
+    let output_task = tokio::spawn(pump_raw(pty.reader().map_err(terminal_error)?, sink.clone()));
+    let stdin_task = match stdin {
+        Some(input) => Some(tokio::spawn(feed_terminal(pty.writer().map_err(terminal_error)?, pty, input))),
+        None => None,
+    };
+
+    let (exit, stop) = wait(&mut reaper, limits, sink, group).await;
+    let duration_ms = started.elapsed().as_millis() as u64;
+    if let Some(task) = stdin_task {
+        task.abort();
+    }
+
+    // As with pipes, whatever the program left running can keep the
+    // terminal open.
+    if let Some(group) = group {
+        group.kill().await;
+    }
+    drain(output_task).await;
+
+    let result = RunResult {
+        exit_code: exit.as_ref().and_then(|exit| exit.status.code()),
+        timed_out: stop == Some(Stop::Timeout),
+        stdin_timed_out: stop == Some(Stop::StdinTimeout),
+        duration_ms,
+        usage: ResourceUsage { program: exit.and_then(|exit| exit.usage), ..Default::default() },
+        ..Default::default()
+    };
+    Ok((result, Vec::new()))
+}
+
+/// Waits for the program to exit, killing it (and its whole cgroup) once it
+/// ran out of time, waited on stdin for too long, or its output went over a
+/// limit, or the client went away. Returns how it exited (`None` if it
+/// couldn't be waited for) and why it was stopped.
+async fn wait(
+    reaper: &mut Reaper,
+    limits: Limits,
+    sink: &Sink,
+    group: Option<&Group>,
+) -> (Option<Exit>, Option<Stop>) {
+    let pid = reaper.pid();
+    let stalled = async {
+        match (limits.stdin, pid) {
+            (Some(limit), Some(pid)) => stall::waiting_for_input(pid, group, limit).await,
+            _ => std::future::pending().await,
+        }
+    };
+    let stop = tokio::select! {
+        exit = reaper.wait() => return (exit.ok(), None),
+        _ = tokio::time::sleep(limits.run) => Stop::Timeout,
+        _ = stalled => Stop::StdinTimeout,
+        _ = sink.limiter.exceeded() => Stop::OutputLimit,
+        _ = sink.events.closed() => Stop::Abandoned,
+    };
+    reaper.kill();
+    if let Some(group) = group {
+        group.kill().await;
+    }
+    (reaper.wait().await.ok(), Some(stop))
+}
+
+/// `supervise` for the wasm mode: the guest runs inside this task, with its
+/// stdio going through in-memory pipes to the same pumps a process uses.
+async fn supervise_wasm(
+    config: &Config,
+    module: &Path,
+    dir: &Path,
+    sink: &Sink,
+    stdin: Option<StdinReceiver>,
+) -> Result<(RunResult, Vec<u8>), RunEvent> {
+    let (stdout_writer, stdout) = tokio::io::duplex(PIPE_CAPACITY);
+    let (stderr_writer, stderr) = tokio::io::duplex(PIPE_CAPACITY);
+    let stdout_task = tokio::spawn(pump(stdout, sink.clone(), Stream::Stdout, false));
+    let stderr_task = tokio::spawn(pump(stderr, sink.clone(), Stream::Stderr, false));
+
+    let (guest_stdin, stdin_task) = match stdin {
+        Some(input) => {
+            let (stdin, writer) = tokio::io::duplex(PIPE_CAPACITY);
+            (stdin, Some(tokio::spawn(feed(writer, input))))
+        }
+        // Reads see EOF right away, like `/dev/null`.
+        None => (tokio::io::duplex(1).0, None),
+    };
+
+    let started = Instant::now();
+    let guest = wasm::run(module, dir, config.wasm_memory_limit, guest_stdin, stdout_writer, stderr_writer);
+    let (outcome, timed_out) = tokio::select! {
+        outcome = guest => (Some(outcome), false),
+        _ = tokio::time::sleep(config.run_timeout) => (None, true),
+        _ = sink.limiter.exceeded() => (None, false),
+        _ = sink.events.closed() => (None, false),
+    };
+    let duration_ms = started.elapsed().as_millis() as u64;
+
+    if let Some(task) = stdin_task {
+        task.abort();
+    }
+    // The guest's ends of the pipes are gone now, so the pumps see EOF.
+    drain(stdout_task).await;
+    drain(stderr_task).await;
+
+    let mut result = RunResult { timed_out, duration_ms, ..Default::default() };
+    match outcome {
+        Some(Ok(wasm::Outcome::Exited(code))) => result.exit_code = Some(code),
+        Some(Ok(wasm::Outcome::Trapped(trap))) => result.trap = Some(trap),
+        Some(Err(message)) => return Err(RunEvent::Error { message }),
+        None => {}
+    }
+    Ok((result, Vec::new()))
+}
+
+async fn drain(task: JoinHandle<Vec<u8>>) -> Vec<u8> {
+    let abort = task.abort_handle();
+    match tokio::time::timeout(Duration::from_secs(1), task).await {
+        Ok(result) => result.unwrap_or_default(),
+        Err(_) => {
+            abort.abort();
+            Vec::new()
+        }
+    }
+}
+
+/// Forwards what is read from `reader` as `stream` output, each read as soon
+/// as it arrives, so prompts without a trailing newline show up right away.
+/// With `capture` set, also returns what was read (output limits aside), up
+/// to `STDERR_CAPTURE_LIMIT`.
+async fn pump<R: AsyncRead + Unpin>(mut reader: R, sink: Sink, stream: Stream, capture: bool) -> Vec<u8> {
+    let mut captured = Vec::new();
+    let mut decoder = utf8::Decoder::default();
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break, // EOF
+            Ok(n) => n,
+        };
+        if capture && captured.len() < STDERR_CAPTURE_LIMIT {
+            let room = STDERR_CAPTURE_LIMIT - captured.len();
+            captured.extend_from_slice(&buf[..n.min(room)]);
+        }
+        let n = sink.limiter.admit(n);
+        if n == 0 {
+            continue;
+        }
+        let event = if sink.raw {
+            RunEvent::Output { stream, data: buf[..n].to_vec() }
+        } else {
+            let text = decoder.decode(&buf[..n]);
+            if text.is_empty() {
+                continue;
+            }
+            stream.text(text)
+        };
+        if sink.events.send(event).await.is_err() {
+            return captured;
+        }
+    }
+    let rest = decoder.finish();
+    if !rest.is_empty() {
+        let _ = sink.events.send(stream.text(rest)).await;
+    }
+    captured
+}
+
+/// Forwards terminal output as-is. Escape sequences and multi-byte
+/// characters may be split across events; terminal emulators expect that.
+async fn pump_raw<R: AsyncRead + Unpin>(mut reader: R, sink: Sink) -> Vec<u8> {
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match reader.read(&mut buf).await {
+            // Reading the master fails with EIO once the program is gone.
+            Ok(0) | Err(_) => break,
+            Ok(n) => sink.limiter.admit(n),
+        };
+        if n > 0 && sink.events.send(RunEvent::Terminal { data: buf[..n].to_vec() }).await.is_err() {
+            break;
+        }
+    }
+    Vec::new()
+}
+
+/// Like `pump`, but line by line, letting `classify` turn each line into an
+/// event. Used for tools that interleave structured output with the program's
+/// own stderr, e.g. Miri's `--error-format=json` diagnostics or libFuzzer's
+/// status lines. A line still unfinished after `PARTIAL_LINE_DELAY` is
+/// forwarded as it is.
+async fn pump_lines<R: AsyncRead + Unpin>(
+    reader: R,
+    sink: Sink,
+    classify: fn(String) -> RunEvent,
+    capture: bool,
+) -> Vec<u8> {
+    let mut captured = Vec::new();
+    let mut decoder = utf8::Decoder::default();
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        // `read_until` keeps what it read so far in `line` when timed out.
+        let read = tokio::time::timeout(PARTIAL_LINE_DELAY, reader.read_until(b'\n', &mut line)).await;
+        let eof = matches!(read, Ok(Ok(0) | Err(_)));
+        if !line.is_empty() {
+            if capture && captured.len() < STDERR_CAPTURE_LIMIT {
+                let room = STDERR_CAPTURE_LIMIT - captured.len();
+                captured.extend_from_slice(&line[..line.len().min(room)]);
+            }
+            line.truncate(sink.limiter.admit(line.len()));
+            let text = decoder.decode(&line);
+            let event = match classify(text) {
+                RunEvent::Stderr { .. } if sink.raw && !line.is_empty() => Some(RunEvent::Output { stream: Stream::Stderr, data: line.clone() }),
+                // Only the start of a character so far.
+                RunEvent::Stderr { data } if data.is_empty() => None,
+                event => Some(event),
+            };
+            line.clear();
+            if let Some(event) = event {
+                if sink.events.send(event).await.is_err() {
+                    return captured;
+                }
+            }
+        }
+        if eof {
+            break;
+        }
+    }
+    let rest = decoder.finish();
+    if !rest.is_empty() {
+        let _ = sink.events.send(RunEvent::Stderr { data: rest }).await;
+    }
+    captured
+}
+
+fn diagnostic_or_stderr(text: String) -> RunEvent {
+    match Diagnostic::from_json_line(&text) {
+        Some(diagnostic) => RunEvent::Diagnostic(diagnostic),
+        None => RunEvent::Stderr { data: text },
+    }
+}
+
+fn fuzz_progress_or_stderr(text: String) -> RunEvent {
+    match fuzz::Progress::from_line(&text) {
+        Some(progress) => RunEvent::FuzzProgress(progress),
+        None => RunEvent::Stderr { data: text },
+    }
+}
+
+/// Writes input to the program's stdin until it's closed, either by an
+/// `Eof` from the client or by the program going away.
+async fn feed<W: AsyncWrite + Unpin>(mut stdin: W, mut input: StdinReceiver) {
+    while let Some(input) = input.recv().await {
+        match input {
+            Input::Data(bytes) => {
+                if stdin.write_all(&bytes).await.is_err() || stdin.flush().await.is_err() {
+                    break;
+                }
+            }
+            Input::Eof => {
+                // Dropping our end closes the pipe.
+                let _ = stdin.shutdown().await;
+                break;
+            }
+            Input::Resize(_) => {}
+        }
+    }
+}
+
+async fn feed_terminal<W: AsyncWrite + Unpin>(mut terminal: W, pty: Pty, mut input: StdinReceiver) {
+    while let Some(input) = input.recv().await {
+        let bytes = match input {
+            Input::Data(bytes) => bytes,
+            // The terminal stays open; ^D makes a pending read return what
+            // was typed so far, or end-of-file on an empty line.
+            Input::Eof => vec![0x04],
+            Input::Resize(size) => {
+                let _ = pty.resize(size);
+                continue;
+            }
+        };
+        if terminal.write_all(&bytes).await.is_err() || terminal.flush().await.is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::fs;
+
+use crate::{artifacts, config::Config};
+
+/// A file put in the program's working directory before it starts.
+#[derive(Deserialize, Clone, Debug)]
+pub struct InputFile {
+    /// Path relative to the working directory, e.g. `data/input.txt`.
+    pub name: String,
+    pub content: String,
+    #[serde(default)]
+    pub encoding: FileEncoding,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FileEncoding {
+    #[default]
+    Text,
+    Base64,
+}
+
+/// A file the program wrote, copied out of the run's directory so it can be
+/// downloaded after the run.
+#[derive(Serialize, Clone, Debug)]
+pub struct OutputFile {
+    /// Path relative to the working directory.
+    pub name: String,
+    pub size: u64,
+    pub url: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct OutputFiles {
+    pub files: Vec<OutputFile>,
+    /// Files left out for going over `FILES_MAX_COUNT` or `FILES_MAX_KB`.
+    pub omitted: u64,
+    /// When the download URLs stop working.
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Modification times of the files in a working directory, to tell which
+/// ones the program wrote.
+pub struct Snapshot(HashMap<PathBuf, SystemTime>);
+
+/// Writes `inputs` under `dir`. Names must be relative and stay inside it.
+pub async fn provision(dir: &Path, inputs: &[InputFile]) -> Result<Snapshot, String> {
+    let mut snapshot = HashMap::new();
+    for input in inputs {
+        let name = Path::new(&input.name);
+        // `components` passes over a trailing `/` or `/.`, which would name a
+        // directory rather than a file
+        let directory = input.name.ends_with(['/', '\\']) || input.name.ends_with("/.");
+        if input.name.is_empty() || directory || !name.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("Invalid input file name: {:?}", input.name));
+        }
+        let content = match input.encoding {
+            FileEncoding::Text => input.content.clone().into_bytes(),
+            FileEncoding::Base64 => STANDARD
+                .decode(&input.content)
+                .map_err(|e| format!("Invalid base64 in input file {:?}: {}", input.name, e))?,
+        };
+        let path = dir.join(name);
+        let write = async {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::write(&path, content).await?;
+            fs::metadata(&path).await?.modified()
+        };
+        let modified = write.await.map_err(|e| format!("Failed to write input file {:?}: {}", input.name, e))?;
+        snapshot.insert(path, modified);
+    }
+    Ok(Snapshot(snapshot))
+}
+
+/// Stores the regular files under `dir` the program created or modified,
+/// in path order, as long as they fit the configured caps. Symlinks and
+/// other special files are ignored.
+pub async fn collect(config: &Config, dir: &Path, snapshot: &Snapshot) -> io::Result<OutputFiles> {
+    let mut written = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let mut entries = fs::read_dir(&current).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            let path = entry.path();
+            if metadata.is_dir() {
+                pending.push(path);
+            } else if metadata.is_file() && snapshot.0.get(&path) != metadata.modified().ok().as_ref() {
+                written.push((path, metadata.len()));
+            }
+        }
+    }
+    written.sort();
+
+    let mut collected = OutputFiles { files: Vec::new(), omitted: 0, expires_at: artifacts::expires_at(config) };
+    let mut budget = config.files_max_bytes;
+    for (path, size) in written {
+        if collected.files.len() >= config.files_max_count || size > budget {
+            collected.omitted += 1;
+            continue;
+        }
+        let name = path.strip_prefix(dir).unwrap_or(&path).to_string_lossy().into_owned();
+        let url = artifacts::store_file(config, &path).await?;
+        budget -= size;
+        collected.files.push(OutputFile { name, size, url });
+    }
+    Ok(collected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(name: &str) -> InputFile {
+        InputFile { name: name.to_string(), content: "x".to_string(), encoding: FileEncoding::Text }
+    }
+
+    /// Provisions a single file named `name` in a fresh directory and
+    /// returns the outcome along with the directory's parent, to look for
+    /// anything written outside of it.
+    async fn provision_one(name: &str) -> (Result<Snapshot, String>, PathBuf) {
+        let parent = std::env::temp_dir().join(format!("files_test_{}", uuid::Uuid::new_v4()));
+        let dir = parent.join("files");
+        std::fs::create_dir_all(&dir).unwrap();
+        (provision(&dir, &[input(name)]).await, parent)
+    }
+
+    #[tokio::test]
+    async fn accepts_relative_names_inside_the_directory() {
+        for name in ["input.txt", "data/input.txt", "a/b/c.bin", ".hidden", "with space.txt"] {
+            let (outcome, parent) = provision_one(name).await;
+            let written = std::fs::read_to_string(parent.join("files").join(name));
+            std::fs::remove_dir_all(&parent).unwrap();
+            assert!(outcome.is_ok(), "{}", name);
+            assert_eq!(written.unwrap(), "x", "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_names_that_lead_elsewhere() {
+        for name in ["", "..", "../escape.txt", "data/../../escape.txt", "./input.txt", "/etc/escape.txt", "data/", "data/."] {
+            let (outcome, parent) = provision_one(name).await;
+            let escaped = std::fs::exists(parent.join("escape.txt")).unwrap();
+            std::fs::remove_dir_all(&parent).unwrap();
+            assert_eq!(outcome.err(), Some(format!("Invalid input file name: {:?}", name)), "{}", name);
+            assert!(!escaped, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_base64_and_reports_bad_input() {
+        let dir = std::env::temp_dir().join(format!("files_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let encoded = InputFile { name: "a.bin".to_string(), content: "AAEC".to_string(), encoding: FileEncoding::Base64 };
+        let broken = InputFile { content: "not base64!".to_string(), ..encoded.clone() };
+        let decoded = provision(&dir, &[encoded]).await.map(|_| std::fs::read(dir.join("a.bin")).unwrap());
+        let failed = provision(&dir, &[broken]).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(decoded.unwrap(), [0, 1, 2]);
+        assert!(failed.is_err_and(|message| message.starts_with("Invalid base64 in input file \"a.bin\"")));
+    }
+}
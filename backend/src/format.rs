@@ -0,0 +1,101 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use std::{process::Stdio, time::Instant};
+use tokio::{io::AsyncWriteExt, process::Command};
+use tokio_util::sync::CancellationToken;
+use utoipa::ToSchema;
+
+use crate::{config::Config, db::AppState};
+
+const EDITIONS: &[&str] = &["2015", "2018", "2021", "2024"];
+
+#[derive(Deserialize, ToSchema)]
+pub struct FormatRequest {
+    pub code: String,
+    /// Edition to parse the code as, `2021` by default.
+    pub edition: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FormatResponse {
+    /// The code could be formatted.
+    pub success: bool,
+    /// The formatted code, or the code as it was sent if it couldn't be.
+    pub code: String,
+    /// What rustfmt reported, such as parse errors.
+    pub message: Option<String>,
+    pub duration_ms: u64,
+}
+
+// POST /format
+#[utoipa::path(
+    post,
+    path = "/format",
+    request_body = FormatRequest,
+    responses(
+        (status = 200, description = "The code formatted with rustfmt", body = FormatResponse),
+        (status = 400, description = "Unknown edition")
+    ),
+    tag = "compiler"
+)]
+pub async fn format_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<FormatRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let response = format(&state.config, payload, CancellationToken::new()).await?;
+    Ok(Json(response.expect("never cancelled")))
+}
+
+/// Runs `request.code` through rustfmt, within `CHECK_TIMEOUT_SECS`. Returns
+/// `None` if `cancel` fires first.
+pub async fn format(
+    config: &Config,
+    request: FormatRequest,
+    cancel: CancellationToken,
+) -> Result<Option<FormatResponse>, (StatusCode, String)> {
+    let edition = request.edition.as_deref().unwrap_or("2021");
+    if !EDITIONS.contains(&edition) {
+        return Err((StatusCode::BAD_REQUEST, format!("Unknown edition: {}", edition)));
+    }
+    let internal = |e: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+
+    let started = Instant::now();
+    let mut rustfmt = Command::new("rustfmt")
+        .args(["--emit=stdout", "--edition", edition])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(internal)?;
+    let mut stdin = rustfmt.stdin.take().expect("stdin is piped");
+    let code = request.code.clone();
+    // Written alongside reading the output, so a large file can't fill
+    // both pipes.
+    tokio::spawn(async move {
+        let _ = stdin.write_all(code.as_bytes()).await;
+    });
+
+    let output = tokio::select! {
+        output = tokio::time::timeout(config.check_timeout, rustfmt.wait_with_output()) => output,
+        _ = cancel.cancelled() => return Ok(None),
+    };
+    let duration_ms = started.elapsed().as_millis() as u64;
+    let Ok(output) = output else {
+        return Ok(Some(FormatResponse {
+            success: false,
+            code: request.code,
+            message: Some("rustfmt timed out".to_string()),
+            duration_ms,
+        }));
+    };
+    let output = output.map_err(internal)?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    let message = (!stderr.is_empty()).then_some(stderr);
+    Ok(Some(if output.status.success() {
+        FormatResponse { success: true, code: String::from_utf8_lossy(&output.stdout).into_owned(), message, duration_ms }
+    } else {
+        FormatResponse { success: false, code: request.code, message, duration_ms }
+    }))
+}
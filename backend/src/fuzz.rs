@@ -0,0 +1,355 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{path::{Path, PathBuf}, time::Duration};
+use tokio::{fs, io::AsyncReadExt};
+
+use crate::{config::Config, sanitizer};
+
+/// Fuzzing time when the request doesn't ask for a specific duration.
+const DEFAULT_DURATION: Duration = Duration::from_secs(30);
+
+/// Extension of `-artifact_prefix` in the workspace, which the names of
+/// libFuzzer's artifacts start with.
+pub const ARTIFACT_PREFIX: &str = "fuzz-";
+
+/// Upper bound for shrinking a crashing input after the session.
+const MINIMIZE_TIME: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct FuzzOptions {
+    /// How long to fuzz, capped by the server's `FUZZ_MAX_SECS`.
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+}
+
+impl FuzzOptions {
+    pub fn duration(self, config: &Config) -> Duration {
+        self.duration_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_DURATION)
+            .min(config.fuzz_max_duration)
+    }
+}
+
+// Stand-in for `libfuzzer_sys::fuzz_target!`, kept on a single line so that
+// line numbers in diagnostics still match what the user wrote.
+const PRELUDE: &str = "#![no_main] macro_rules! fuzz_target { (|$data:ident: &[u8]| $body:block) => { \
+#[no_mangle] pub extern \"C\" fn LLVMFuzzerTestOneInput(ptr: *const u8, len: usize) -> i32 { \
+let $data: &[u8] = if len == 0 { &[] } else { unsafe { ::std::slice::from_raw_parts(ptr, len) } }; \
+$body 0 } }; } ";
+
+/// Turns a cargo-fuzz style target into a crate libFuzzer can drive. The
+/// `#![no_main]` and `use libfuzzer_sys::fuzz_target;` lines people copy from
+/// cargo-fuzz templates are blanked out since the prelude provides both.
+pub fn harness(code: &str) -> String {
+    let body: Vec<&str> = code
+        .lines()
+        .map(|line| match line.trim() {
+            "#![no_main]" | "use libfuzzer_sys::fuzz_target;" => "",
+            _ => line,
+        })
+        .collect();
+    format!("{}{}", PRELUDE, body.join("\n"))
+}
+
+/// `rustc` flags for a libFuzzer build: ASan plus SanitizerCoverage
+/// instrumentation, linked against the libFuzzer runtime.
+pub fn rustc_args(libfuzzer: &Path) -> Vec<String> {
+    [
+        "-Zsanitizer=address",
+        "-Cpasses=sancov-module",
+        "-Cllvm-args=-sanitizer-coverage-level=4",
+        "-Cllvm-args=-sanitizer-coverage-inline-8bit-counters",
+        "-Cllvm-args=-sanitizer-coverage-pc-table",
+        "-Cllvm-args=-sanitizer-coverage-trace-compares",
+        "-Cpanic=abort",
+        "-Copt-level=1",
+        "-Cdebuginfo=1",
+        "-Cdebug-assertions",
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .chain([
+        format!("-Clink-arg={}", libfuzzer.display()),
+        "-Clink-arg=-lstdc++".to_string(),
+    ])
+    .collect()
+}
+
+/// Arguments for the fuzzing session itself. Crash artifacts are written next
+/// to the binary, their names starting with `artifact_prefix`.
+pub fn session_args(duration: Duration, artifact_prefix: &Path) -> Vec<String> {
+    vec![
+        format!("-max_total_time={}", duration.as_secs()),
+        format!("-artifact_prefix={}", artifact_prefix.display()),
+        "-print_final_stats=1".to_string(),
+    ]
+}
+
+/// A libFuzzer status line such as
+/// `#4096 pulse  cov: 41 ft: 52 corp: 6/18b lim: 43 exec/s: 2048 rss: 31Mb`.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Progress {
+    pub runs: u64,
+    /// `INITED`, `NEW`, `REDUCE`, `pulse`, `DONE`, ...
+    pub status: String,
+    pub coverage: u64,
+    pub features: u64,
+    pub corpus_entries: u64,
+    pub exec_per_sec: u64,
+    pub rss_mb: u64,
+}
+
+impl Progress {
+    pub fn from_line(line: &str) -> Option<Self> {
+        let rest = line.strip_prefix('#')?;
+        let mut tokens = rest.split_whitespace();
+        let runs = tokens.next()?.parse().ok()?;
+        let status = tokens.next()?.to_string();
+
+        let mut progress = Progress { runs, status, ..Default::default() };
+        while let Some(key) = tokens.next() {
+            let Some(value) = tokens.next() else { break };
+            let number = |v: &str| {
+                v.trim_end_matches("Mb")
+                    .split('/')
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or(0)
+            };
+            match key {
+                "cov:" => progress.coverage = number(value),
+                "ft:" => progress.features = number(value),
+                "corp:" => progress.corpus_entries = number(value),
+                "exec/s:" => progress.exec_per_sec = number(value),
+                "rss:" => progress.rss_mb = number(value),
+                _ => {}
+            }
+        }
+        Some(progress)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Report {
+    /// Last status line libFuzzer printed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Progress>,
+    pub crashes: Vec<Crash>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Crash {
+    /// `crash`, `leak`, `timeout` or `oom`, from libFuzzer's artifact name.
+    pub kind: String,
+    /// The input that triggered it, base64 encoded.
+    pub input: String,
+    /// The smallest input libFuzzer could shrink it to, base64 encoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minimized: Option<String>,
+    /// Panic message or sanitizer headline.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Where libFuzzer saved the input.
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// Length of the input, in bytes.
+    #[serde(skip)]
+    pub size: usize,
+}
+
+/// libFuzzer's artifact kinds, as they start the names of the files it saves.
+const ARTIFACT_KINDS: [&str; 5] = ["crash", "leak", "timeout", "oom", "slow-unit"];
+
+/// Crash inputs bigger than this are left out of the report. libFuzzer's own
+/// are at most `-max_len`, 4 KiB by default.
+const MAX_INPUT_SIZE: u64 = 1024 * 1024;
+
+/// Most crash inputs reported from one session.
+const MAX_CRASHES: usize = 16;
+
+/// Collects the crashing inputs libFuzzer saved under `artifact_prefix`. The
+/// files are found by listing the directory, never from paths the program
+/// printed, and only regular files are read.
+pub async fn report(artifact_prefix: &Path, stderr: &str) -> Report {
+    let stats = stderr.lines().filter_map(Progress::from_line).next_back();
+    let message = failure_message(stderr);
+
+    let (Some(dir), Some(prefix)) = (artifact_prefix.parent(), artifact_prefix.file_name()) else {
+        return Report { stats, crashes: Vec::new() };
+    };
+    let prefix = prefix.to_string_lossy();
+    let mut found = Vec::new();
+    if let Ok(mut entries) = fs::read_dir(dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some(kind) = name.strip_prefix(prefix.as_ref()).and_then(artifact_kind) else {
+                continue;
+            };
+            found.push((name, kind, entry.path()));
+        }
+    }
+    // Directory order isn't stable
+    found.sort();
+
+    let mut crashes = Vec::new();
+    for (_, kind, path) in found {
+        if crashes.len() == MAX_CRASHES {
+            break;
+        }
+        let Some(input) = read_artifact(&path).await else {
+            continue;
+        };
+        crashes.push(Crash {
+            kind: kind.to_string(),
+            input: STANDARD.encode(&input),
+            minimized: None,
+            message: message.clone(),
+            path,
+            size: input.len(),
+        });
+    }
+
+    Report { stats, crashes }
+}
+
+/// The kind of an artifact from its name after the prefix, e.g. `crash-<sha1>`.
+fn artifact_kind(name: &str) -> Option<&'static str> {
+    ARTIFACT_KINDS
+        .into_iter()
+        .find(|kind| name.strip_prefix(kind).is_some_and(|rest| rest.starts_with('-')))
+}
+
+/// Reads a file the fuzzer saved, if it is a regular file of a sensible size.
+/// The program can leave symlinks in the workspace, so they aren't
+/// followed.
+pub async fn read_artifact(path: &Path) -> Option<Vec<u8>> {
+    let metadata = fs::symlink_metadata(path).await.ok()?;
+    if !metadata.is_file() || metadata.len() > MAX_INPUT_SIZE {
+        return None;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.read(true);
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW);
+    let file = options.open(path).await.ok()?;
+    let mut input = Vec::new();
+    file.take(MAX_INPUT_SIZE).read_to_end(&mut input).await.ok()?;
+    Some(input)
+}
+
+/// Arguments that make the fuzz target shrink `input` for up to
+/// `MINIMIZE_TIME`, writing the result to `output`.
+pub fn minimize_args(input: &Path, output: &Path, duration: Duration) -> Vec<String> {
+    vec![
+        "-minimize_crash=1".to_string(),
+        format!("-max_total_time={}", duration.min(MINIMIZE_TIME).as_secs().max(1)),
+        format!("-exact_artifact_path={}", output.display()),
+        input.display().to_string(),
+    ]
+}
+
+/// Options for running the target in `-minimize_crash` mode, whose time
+/// limit is that of a session this long.
+pub fn minimize_options(duration: Duration) -> FuzzOptions {
+    FuzzOptions { duration_secs: Some(duration.min(MINIMIZE_TIME).as_secs().max(1)) }
+}
+
+// Prefer the panic message, then a sanitizer headline, then libFuzzer's own
+// "deadly signal" style error.
+fn failure_message(stderr: &str) -> Option<String> {
+    let lines: Vec<&str> = stderr.lines().collect();
+    if let Some(pos) = lines.iter().position(|l| l.contains("panicked at")) {
+        let mut message = lines[pos].trim().to_string();
+        // Since Rust 1.73 the message itself is on the following lines.
+        for line in &lines[pos + 1..] {
+            if line.is_empty() || line.starts_with("note:") || line.starts_with("stack backtrace:") || line.starts_with("==") {
+                break;
+            }
+            message.push('\n');
+            message.push_str(line);
+        }
+        return Some(message);
+    }
+    if let Some(finding) = sanitizer::parse_reports(stderr).into_iter().next() {
+        return Some(finding.message);
+    }
+    lines
+        .iter()
+        .find_map(|l| l.split("ERROR: libFuzzer: ").nth(1))
+        .map(|m| format!("libFuzzer: {}", m.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_status_lines() {
+        let progress = Progress::from_line("#4096\tpulse  cov: 41 ft: 52 corp: 6/18b lim: 43 exec/s: 2048 rss: 31Mb").unwrap();
+        assert_eq!(progress.runs, 4096);
+        assert_eq!(progress.status, "pulse");
+        assert_eq!((progress.coverage, progress.features, progress.corpus_entries), (41, 52, 6));
+        assert_eq!((progress.exec_per_sec, progress.rss_mb), (2048, 31));
+
+        let inited = Progress::from_line("#2\tINITED cov: 3 ft: 3 corp: 1/1b exec/s: 0 rss: 30Mb").unwrap();
+        assert_eq!((inited.status.as_str(), inited.corpus_entries), ("INITED", 1));
+    }
+
+    #[test]
+    fn keeps_a_status_line_missing_fields() {
+        let progress = Progress::from_line("#128 DONE   cov: 7 ft:").unwrap();
+        assert_eq!((progress.runs, progress.status.as_str()), (128, "DONE"));
+        assert_eq!((progress.coverage, progress.features, progress.rss_mb), (7, 0, 0));
+    }
+
+    #[test]
+    fn rejects_other_lines() {
+        assert!(Progress::from_line("INFO: Seed: 1234").is_none());
+        assert!(Progress::from_line("#").is_none());
+        assert!(Progress::from_line("#abc NEW cov: 1").is_none());
+        assert!(Progress::from_line("#12").is_none());
+    }
+
+    #[test]
+    fn tells_artifact_kinds_by_name() {
+        assert_eq!(artifact_kind("crash-da39a3ee"), Some("crash"));
+        assert_eq!(artifact_kind("slow-unit-da39a3ee"), Some("slow-unit"));
+        assert_eq!(artifact_kind("oom-1"), Some("oom"));
+        assert_eq!(artifact_kind("crashes"), None);
+        assert_eq!(artifact_kind("minimized"), None);
+    }
+
+    #[test]
+    fn prefers_the_panic_message() {
+        let stderr = "thread '<unnamed>' panicked at main.rs:5:9:\nboom\nnote: run with `RUST_BACKTRACE=1`\n==1== ERROR: libFuzzer: deadly signal\n";
+        assert_eq!(failure_message(stderr).as_deref(), Some("thread '<unnamed>' panicked at main.rs:5:9:\nboom"));
+        let stderr = "==1== ERROR: libFuzzer: out-of-memory (malloc(1073741824))\n";
+        assert_eq!(failure_message(stderr).as_deref(), Some("libFuzzer: out-of-memory (malloc(1073741824))"));
+        assert_eq!(failure_message("#1 INITED cov: 1\n"), None);
+    }
+
+    #[tokio::test]
+    async fn reports_only_regular_files_under_the_prefix() {
+        let dir = std::env::temp_dir().join(format!("fuzz_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("main.fuzz-");
+        std::fs::write(dir.join("main.fuzz-crash-b"), b"FUZZ").unwrap();
+        std::fs::write(dir.join("main.fuzz-leak-a"), b"L").unwrap();
+        std::fs::write(dir.join("main.fuzz-notes"), b"not an artifact").unwrap();
+        std::fs::write(dir.join("crash-elsewhere"), b"no prefix").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("/etc/hostname", dir.join("main.fuzz-crash-link")).unwrap();
+
+        // Paths the program prints are not where inputs are looked for
+        let stderr = "#10 DONE cov: 2\nTest unit written to /etc/hostname\n";
+        let report = report(&prefix, stderr).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(report.stats.map(|stats| stats.runs), Some(10));
+        let found: Vec<(&str, &str)> =
+            report.crashes.iter().map(|crash| (crash.kind.as_str(), crash.input.as_str())).collect();
+        assert_eq!(found, [("crash", "RlVaWg=="), ("leak", "TA==")]);
+        assert_eq!(report.crashes[0].size, 4);
+    }
+}
@@ -0,0 +1,228 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::SystemTime,
+};
+use utoipa::ToSchema;
+
+use crate::config::Config;
+
+/// Entries the server creates directly in the temp directory: run
+/// workspaces, language server projects and the loose files of versions
+/// that kept every run in one flat directory. Anything else is left alone.
+const PREFIXES: [&str; 3] = ["run_", "lsp_", "temp_"];
+
+/// Directories in use, which a pass never removes, and how many of `BYTES`
+/// are theirs.
+static LIVE: LazyLock<Mutex<HashMap<PathBuf, u64>>> = LazyLock::new(Default::default);
+
+/// Bytes in the temp directory: the last pass's measurement, plus what was
+/// stored since, less what directories in use that went away since had when
+/// it was taken. What programs write as they run goes uncounted until the
+/// next pass.
+static BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// The last pass's measurements.
+static USAGE: Mutex<Option<TempUsage>> = Mutex::new(None);
+
+/// Only one pass runs at a time.
+static PASS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Disk use of the temp directory, as of the last pass.
+#[derive(Serialize, ToSchema, Clone, Debug)]
+pub struct TempUsage {
+    pub bytes: u64,
+    /// `0` means no quota.
+    pub quota_bytes: u64,
+    /// Run and language server directories in use.
+    pub live: usize,
+    /// Downloadable build outputs and program files still kept.
+    pub artifacts: usize,
+    /// Entries the pass removed.
+    pub removed: usize,
+    pub measured_at: DateTime<Utc>,
+}
+
+/// Keeps passes away from a directory for as long as it lives.
+pub struct Hold(PathBuf);
+
+impl Hold {
+    pub fn new(dir: &Path) -> Self {
+        LIVE.lock().unwrap().insert(dir.to_path_buf(), 0);
+        Self(dir.to_path_buf())
+    }
+}
+
+impl Drop for Hold {
+    fn drop(&mut self) {
+        if let Some(counted) = LIVE.lock().unwrap().remove(&self.0) {
+            let _ = BYTES.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bytes| Some(bytes.saturating_sub(counted)));
+        }
+    }
+}
+
+/// Counts `bytes` just stored in the temp directory, e.g. an artifact.
+pub fn stored(bytes: u64) {
+    BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn usage() -> Option<TempUsage> {
+    USAGE.lock().unwrap().clone()
+}
+
+/// Fails with `StorageFull` if the temp directory is over its quota, even
+/// after removing whatever may go. Only when the running count says it's
+/// over is the directory measured again and swept.
+pub async fn admit(config: &Config) -> io::Result<()> {
+    if config.temp_quota == 0 || BYTES.load(Ordering::Relaxed) < config.temp_quota {
+        return Ok(());
+    }
+    if sweep(config, false).await?.bytes < config.temp_quota {
+        return Ok(());
+    }
+    Err(io::Error::new(io::ErrorKind::StorageFull, "temp storage is over its quota"))
+}
+
+/// Sweeps the temp directory right away, then every `GC_INTERVAL_SECS`.
+/// Nothing is in use before the server starts serving, so the first pass
+/// removes every workspace a previous instance left behind.
+pub async fn start(config: Arc<Config>) {
+    match sweep(&config, true).await {
+        Ok(usage) if usage.removed > 0 => println!("Removed {} stale temp entries", usage.removed),
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to sweep {}: {}", config.temp_dir.display(), e),
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.gc_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = sweep(&config, false).await {
+                eprintln!("Failed to sweep {}: {}", config.temp_dir.display(), e);
+            }
+        }
+    });
+}
+
+/// Removes workspaces older than `TEMP_MAX_AGE_SECS` (all of them, on
+/// `startup`) and artifacts past `ARTIFACT_TTL_SECS`, then the oldest of the rest while the temp
+/// directory is over its quota.
+async fn sweep(config: &Config, startup: bool) -> io::Result<TempUsage> {
+    let _pass = PASS.lock().await;
+    let config = config.clone();
+    let usage = tokio::task::spawn_blocking(move || sweep_blocking(&config, startup))
+        .await
+        .map_err(io::Error::other)??;
+    BYTES.store(usage.bytes, Ordering::Relaxed);
+    *USAGE.lock().unwrap() = Some(usage.clone());
+    Ok(usage)
+}
+
+struct Entry {
+    path: PathBuf,
+    modified: SystemTime,
+    bytes: u64,
+    artifact: bool,
+}
+
+fn sweep_blocking(config: &Config, startup: bool) -> io::Result<TempUsage> {
+    fs::create_dir_all(&config.temp_dir)?;
+    let mut total = size(&config.temp_dir);
+    let live: Vec<PathBuf> = LIVE.lock().unwrap().keys().cloned().collect();
+    // What the directories in use hold now is part of `total`
+    let sizes: Vec<u64> = live.iter().map(|dir| size(dir)).collect();
+    let mut held = LIVE.lock().unwrap();
+    for (dir, bytes) in live.iter().zip(sizes) {
+        if let Some(counted) = held.get_mut(dir) {
+            *counted = bytes;
+        }
+    }
+    drop(held);
+
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(&config.temp_dir)?.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // Held paths are absolute.
+        let held = std::path::absolute(entry.path()).is_ok_and(|path| live.contains(&path));
+        if PREFIXES.iter().any(|prefix| name.starts_with(prefix)) && !held {
+            entries.extend(describe(entry.path(), false));
+        }
+    }
+    let artifacts = config.temp_dir.join("artifacts");
+    for dir in [artifacts.clone(), artifacts.join("files")] {
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            if entry.file_name() != "files" || dir != artifacts {
+                entries.extend(describe(entry.path(), true));
+            }
+        }
+    }
+
+    let now = SystemTime::now();
+    let expired = |entry: &Entry| {
+        let max_age = if entry.artifact { config.artifact_ttl } else { config.temp_max_age };
+        (startup && !entry.artifact) || now.duration_since(entry.modified).is_ok_and(|age| age > max_age)
+    };
+    let (expired, mut kept): (Vec<Entry>, Vec<Entry>) = entries.into_iter().partition(expired);
+    let mut removed = 0;
+    for entry in expired {
+        if remove(&entry.path) {
+            total = total.saturating_sub(entry.bytes);
+            removed += 1;
+        }
+    }
+
+    kept.sort_by_key(|entry| entry.modified);
+    let mut kept = kept.into_iter();
+    while config.temp_quota > 0 && total > config.temp_quota {
+        let Some(entry) = kept.next() else { break };
+        if remove(&entry.path) {
+            total = total.saturating_sub(entry.bytes);
+            removed += 1;
+        }
+    }
+
+    Ok(TempUsage {
+        bytes: total,
+        quota_bytes: config.temp_quota,
+        live: live.len(),
+        artifacts: kept.filter(|entry| entry.artifact).count(),
+        removed,
+        measured_at: Utc::now(),
+    })
+}
+
+fn describe(path: PathBuf, artifact: bool) -> Option<Entry> {
+    let modified = fs::symlink_metadata(&path).and_then(|metadata| metadata.modified()).ok()?;
+    Some(Entry { bytes: size(&path), path, modified, artifact })
+}
+
+/// Bytes in the files under `path`, without following symlinks.
+fn size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| size(&entry.path()))
+        .sum()
+}
+
+fn remove(path: &Path) -> bool {
+    let removed = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        _ => fs::remove_file(path),
+    };
+    removed.is_ok()
+}
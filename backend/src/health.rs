@@ -0,0 +1,35 @@
+use axum::{extract::State, Json};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    db::AppState,
+    gc::{self, TempUsage},
+    ratelimit::RateLimitStats,
+};
+
+#[derive(Serialize, ToSchema)]
+pub struct HealthResponse {
+    pub status: String,
+    /// Disk use of `TEMP_DIR`, as of the last sweep.
+    pub temp: Option<TempUsage>,
+    /// Requests let through and refused by each rate limit.
+    pub rate_limits: RateLimitStats,
+}
+
+// GET /health
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses(
+        (status = 200, description = "The server is up", body = HealthResponse)
+    ),
+    tag = "health"
+)]
+pub async fn health_handler(State(state): State<AppState>) -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "ok".to_string(),
+        temp: gc::usage(),
+        rate_limits: state.rate_limits.stats(),
+    })
+}
@@ -0,0 +1,341 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, Pool, Sqlite};
+use std::time::Instant;
+use tokio::sync::mpsc;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    auth::Claims,
+    db::AppState,
+    executor::{CodeRequest, OutputEncoding, RunEvent, RunMode, RunResult},
+    netns::Network,
+    pty::TerminalSize,
+};
+
+/// What a run was asked to do, apart from the code.
+#[derive(Serialize)]
+struct RunOptions {
+    mode: RunMode,
+    terminal: Option<TerminalSize>,
+    output: OutputEncoding,
+    network: Network,
+    /// Names of the input files.
+    files: Vec<String>,
+    target: Option<String>,
+}
+
+/// How a run ended.
+#[derive(Clone, Copy, PartialEq)]
+enum Verdict {
+    /// The program exited with code 0, or the build succeeded.
+    Success,
+    /// The program exited with another code, or was killed by a signal.
+    Failure,
+    Timeout,
+    StdinTimeout,
+    /// The program was killed for its output.
+    OutputLimit,
+    CompileError,
+    /// The run couldn't be carried out.
+    Error,
+    /// The client went away or stopped the run before it finished.
+    Cancelled,
+}
+
+impl Verdict {
+    fn as_str(self) -> &'static str {
+        match self {
+            Verdict::Success => "success",
+            Verdict::Failure => "failure",
+            Verdict::Timeout => "timeout",
+            Verdict::StdinTimeout => "stdin_timeout",
+            Verdict::OutputLimit => "output_limit",
+            Verdict::CompileError => "compile_error",
+            Verdict::Error => "error",
+            Verdict::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Records a signed-in user's run in their history, from the events the run
+/// reports.
+pub struct Recorder {
+    db: Pool<Sqlite>,
+    user_id: String,
+    code_hash: String,
+    options: String,
+    started: Instant,
+    verdict: Option<Verdict>,
+    killed_for_output: bool,
+    result: Option<Box<RunResult>>,
+    output: String,
+    output_limit: usize,
+    truncated: bool,
+}
+
+impl Recorder {
+    pub fn new(state: &AppState, claims: &Claims, request: &CodeRequest) -> Self {
+        let options = RunOptions {
+            mode: request.mode,
+            terminal: request.terminal,
+            output: request.output,
+            network: request.network,
+            files: request.files.iter().map(|file| file.name.clone()).collect(),
+            target: request.target.clone(),
+        };
+        Self {
+            db: state.db.clone(),
+            user_id: claims.sub.clone(),
+            code_hash: format!("{:x}", Sha256::digest(request.code.as_bytes())),
+            options: serde_json::to_string(&options).expect("run options always serialize"),
+            started: Instant::now(),
+            verdict: None,
+            killed_for_output: false,
+            result: None,
+            output: String::new(),
+            output_limit: state.config.history_output_limit,
+            truncated: false,
+        }
+    }
+
+    /// Passes the run's events on, and stores the entry once they end.
+    /// Dropping the returned receiver stops the run, as dropping `events`
+    /// would.
+    pub fn tee(mut self, mut events: mpsc::Receiver<RunEvent>) -> mpsc::Receiver<RunEvent> {
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => match event {
+                        Some(event) => event,
+                        None => break,
+                    },
+                    _ = tx.closed() => break,
+                };
+                self.observe(&event);
+                if tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+            drop(events);
+            if let Err(e) = self.save().await {
+                eprintln!("Failed to record run: {}", e);
+            }
+        });
+        rx
+    }
+
+    fn observe(&mut self, event: &RunEvent) {
+        match event {
+            RunEvent::Stdout { data } | RunEvent::Stderr { data } => self.keep(data.as_bytes()),
+            RunEvent::Output { data, .. } | RunEvent::Terminal { data } => self.keep(data),
+            RunEvent::CompileError { data } => {
+                self.keep(data.as_bytes());
+                self.verdict = Some(Verdict::CompileError);
+            }
+            RunEvent::OutputLimit(truncation) => self.killed_for_output = truncation.killed,
+            RunEvent::Error { .. } => {
+                self.verdict.get_or_insert(Verdict::Error);
+            }
+            RunEvent::Exit(result) => {
+                self.verdict = Some(if result.timed_out {
+                    Verdict::Timeout
+                } else if result.stdin_timed_out {
+                    Verdict::StdinTimeout
+                } else if self.killed_for_output {
+                    Verdict::OutputLimit
+                } else if result.exit_code == Some(0) {
+                    Verdict::Success
+                } else {
+                    Verdict::Failure
+                });
+                self.result = Some(result.clone());
+            }
+            _ => {}
+        }
+    }
+
+    /// Appends program output to the entry, up to `HISTORY_OUTPUT_KB`.
+    fn keep(&mut self, data: &[u8]) {
+        let room = self.output_limit.saturating_sub(self.output.len());
+        if data.len() > room {
+            self.truncated = true;
+        }
+        let text = String::from_utf8_lossy(&data[..data.len().min(room)]);
+        // Replacement characters can make it longer than what was kept.
+        let mut end = text.len().min(room);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.output.push_str(&text[..end]);
+    }
+
+    async fn save(self) -> Result<(), sqlx::Error> {
+        let verdict = self.verdict.unwrap_or(Verdict::Cancelled);
+        let result = self.result.as_ref().map(|result| serde_json::to_string(result).expect("run results always serialize"));
+        sqlx::query(
+            "INSERT INTO runs (id, user_id, code_hash, options, verdict, exit_code, duration_ms, total_ms, result, output, output_truncated)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(&self.user_id)
+        .bind(&self.code_hash)
+        .bind(&self.options)
+        .bind(verdict.as_str())
+        .bind(self.result.as_ref().and_then(|result| result.exit_code))
+        .bind(self.result.as_ref().map(|result| result.duration_ms as i64))
+        .bind(self.started.elapsed().as_millis() as i64)
+        .bind(result)
+        .bind(&self.output)
+        .bind(self.truncated)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+}
+
+#[derive(FromRow)]
+struct RunRow {
+    id: String,
+    code_hash: String,
+    options: String,
+    verdict: String,
+    exit_code: Option<i32>,
+    duration_ms: Option<i64>,
+    total_ms: i64,
+    result: Option<String>,
+    output: String,
+    output_truncated: bool,
+    created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RunSummary {
+    pub id: String,
+    /// SHA-256 of the code, in hex.
+    pub code_hash: String,
+    /// The request's `mode`, `terminal`, `output`, `network` and `target`,
+    /// and the names of its `files`.
+    #[schema(value_type = Object)]
+    pub options: serde_json::Value,
+    /// `success`, `failure`, `timeout`, `stdin_timeout`, `output_limit`,
+    /// `compile_error`, `error` or `cancelled`.
+    pub verdict: String,
+    pub exit_code: Option<i32>,
+    /// How long the program ran.
+    pub duration_ms: Option<i64>,
+    /// How long the whole run took, building included.
+    pub total_ms: i64,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RunDetails {
+    #[serde(flatten)]
+    pub summary: RunSummary,
+    /// The `exit` event of the run, when it got that far.
+    #[schema(value_type = Option<Object>)]
+    pub result: Option<serde_json::Value>,
+    /// Output and compiler errors, up to `HISTORY_OUTPUT_KB`.
+    pub output: String,
+    pub output_truncated: bool,
+}
+
+impl RunRow {
+    fn summary(&self) -> RunSummary {
+        RunSummary {
+            id: self.id.clone(),
+            code_hash: self.code_hash.clone(),
+            options: serde_json::from_str(&self.options).unwrap_or_default(),
+            verdict: self.verdict.clone(),
+            exit_code: self.exit_code,
+            duration_ms: self.duration_ms,
+            total_ms: self.total_ms,
+            created_at: self.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct Page {
+    /// Most runs to return, 50 by default and at most 200.
+    pub limit: Option<u32>,
+    /// Runs to skip, newest first.
+    pub offset: Option<u32>,
+}
+
+// GET /runs
+#[utoipa::path(
+    get,
+    path = "/runs",
+    params(Page),
+    responses(
+        (status = 200, description = "The user's runs, newest first", body = Vec<RunSummary>),
+        (status = 401, description = "Unauthorized")
+    ),
+    tag = "history",
+    security(
+        ("jwt" = [])
+    )
+)]
+pub async fn list_runs(
+    State(state): State<AppState>,
+    claims: Claims,
+    Query(page): Query<Page>,
+) -> Result<Json<Vec<RunSummary>>, (StatusCode, String)> {
+    let runs = sqlx::query_as::<_, RunRow>(
+        "SELECT * FROM runs WHERE user_id = ? ORDER BY created_at DESC, rowid DESC LIMIT ? OFFSET ?",
+    )
+    .bind(&claims.sub)
+    .bind(page.limit.unwrap_or(50).min(200))
+    .bind(page.offset.unwrap_or(0))
+    .fetch_all(&state.db)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(Json(runs.iter().map(RunRow::summary).collect()))
+}
+
+// GET /runs/:id
+#[utoipa::path(
+    get,
+    path = "/runs/{id}",
+    params(
+        ("id" = String, Path, description = "Run ID")
+    ),
+    responses(
+        (status = 200, description = "The run, with its result and output", body = RunDetails),
+        (status = 404, description = "Run not found"),
+        (status = 401, description = "Unauthorized")
+    ),
+    tag = "history",
+    security(
+        ("jwt" = [])
+    )
+)]
+pub async fn get_run(
+    State(state): State<AppState>,
+    claims: Claims,
+    Path(id): Path<String>,
+) -> Result<Json<RunDetails>, (StatusCode, String)> {
+    let run = sqlx::query_as::<_, RunRow>("SELECT * FROM runs WHERE id = ? AND user_id = ?")
+        .bind(&id)
+        .bind(&claims.sub)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "Run not found".to_string()))?;
+
+    Ok(Json(RunDetails {
+        summary: run.summary(),
+        result: run.result.as_deref().and_then(|result| serde_json::from_str(result).ok()),
+        output: run.output,
+        output_truncated: run.output_truncated,
+    }))
+}
@@ -0,0 +1,102 @@
+use axum::extract::ws::{CloseFrame, Message};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::config::Config;
+
+/// Keeps track of whether a WebSocket client is still there. The client is
+/// pinged every `WS_PING_SECS` and let go once a ping went unanswered, and
+/// the connection is closed after `WS_IDLE_SECS` without messages either
+/// way. Pings and pongs don't count as messages.
+#[derive(Clone)]
+pub struct Keepalive {
+    ping_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    times: Arc<Mutex<Times>>,
+}
+
+struct Times {
+    /// Last frame of any kind from the client.
+    heard: Instant,
+    /// Last message either way.
+    active: Instant,
+    /// Last ping, or when the connection opened.
+    pinged: Instant,
+}
+
+/// What the connection needs next.
+pub enum Beat {
+    Ping,
+    Close(&'static str),
+}
+
+impl Keepalive {
+    pub fn new(config: &Config) -> Self {
+        let now = Instant::now();
+        Self {
+            ping_interval: config.ws_ping_interval,
+            idle_timeout: config.ws_idle_timeout,
+            times: Arc::new(Mutex::new(Times { heard: now, active: now, pinged: now })),
+        }
+    }
+
+    /// Notes a frame from the client.
+    pub fn received(&self, message: &Message) {
+        let mut times = self.times.lock().unwrap();
+        times.heard = Instant::now();
+        if !matches!(message, Message::Ping(_) | Message::Pong(_)) {
+            times.active = times.heard;
+        }
+    }
+
+    /// Notes a message sent to the client.
+    pub fn sent(&self) {
+        self.times.lock().unwrap().active = Instant::now();
+    }
+
+    /// Completes when a ping is due or the connection should be closed.
+    pub async fn next(&self) -> Beat {
+        loop {
+            let now = Instant::now();
+            let mut deadline = None::<Instant>;
+            {
+                let mut times = self.times.lock().unwrap();
+                if let Some(idle) = self.idle_timeout {
+                    if now >= times.active + idle {
+                        return Beat::Close("Idle timeout");
+                    }
+                    deadline = Some(times.active + idle);
+                }
+                if let Some(interval) = self.ping_interval {
+                    let due = times.pinged + interval;
+                    if now >= due {
+                        if times.heard < times.pinged {
+                            return Beat::Close("No answer to ping");
+                        }
+                        times.pinged = now;
+                        return Beat::Ping;
+                    }
+                    deadline = Some(deadline.map_or(due, |deadline| deadline.min(due)));
+                }
+            }
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+}
+
+impl Beat {
+    /// The frame to send for this beat.
+    pub fn message(&self) -> Message {
+        match self {
+            Beat::Ping => Message::Ping(Vec::new()),
+            // 1001: going away
+            Beat::Close(reason) => Message::Close(Some(CloseFrame { code: 1001, reason: (*reason).into() })),
+        }
+    }
+}
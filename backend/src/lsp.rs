@@ -0,0 +1,318 @@
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::StatusCode,
+    response::IntoResponse,
+};
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde_json::{json, Value};
+use std::{io, path::PathBuf, process::Stdio, sync::Arc};
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStdin, Command};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::{config::Config, db::AppState, gc};
+
+/// Root clients use for the project's URIs, e.g. `file:///playground/src/main.rs`.
+/// It is swapped for the session's scratch directory in both directions, so
+/// server paths never reach the client.
+const VIRTUAL_ROOT: &str = "/playground";
+
+const CARGO_TOML: &str = "[package]\nname = \"playground\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n";
+
+// Settings rust-analyzer runs with, whatever the client asks for. Options such
+// as `cargo.buildScripts.overrideCommand` would let a client run arbitrary
+// commands on the server, and build scripts or proc macros would run user code.
+fn settings() -> Value {
+    json!({
+        "cargo": { "buildScripts": { "enable": false } },
+        "procMacro": { "enable": false },
+        "checkOnSave": false,
+        "files": { "watcher": "server" },
+    })
+}
+
+// GET /lsp
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let permit = state.lsp_sessions.clone().try_acquire_owned().map_err(|_| {
+        (StatusCode::SERVICE_UNAVAILABLE, "Too many language server sessions, try again later".to_string())
+    })?;
+
+    Ok(ws.on_upgrade(move |socket| async move {
+        session(socket, state.config).await;
+        drop(permit);
+    }))
+}
+
+/// One rust-analyzer process with its scratch project, for as long as the
+/// client stays connected and active.
+async fn session(socket: WebSocket, config: Arc<Config>) {
+    let scratch = match Scratch::create(&config).await {
+        Ok(scratch) => scratch,
+        Err(e) => {
+            eprintln!("Failed to create LSP scratch project: {}", e);
+            return;
+        }
+    };
+
+    let child = Command::new(&config.rust_analyzer)
+        .current_dir(&scratch.dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed to start rust-analyzer: {}", e);
+            scratch.remove().await;
+            return;
+        }
+    };
+
+    let mut stdin = child.stdin.take().expect("child did not have a handle to stdin");
+    let stdout = child.stdout.take().expect("child did not have a handle to stdout");
+    let (server_tx, mut server_rx) = mpsc::channel::<String>(64);
+    let reader = tokio::spawn(read_messages(stdout, server_tx));
+
+    let (mut sender, mut receiver) = socket.split();
+    let idle = tokio::time::sleep(config.lsp_idle_timeout);
+    tokio::pin!(idle);
+
+    let close_reason = loop {
+        tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    idle.as_mut().reset(Instant::now() + config.lsp_idle_timeout);
+                    let Ok(mut message) = serde_json::from_str::<Value>(&scratch.to_server(&text)) else {
+                        continue;
+                    };
+                    if !restrict(&mut message) {
+                        continue;
+                    }
+                    scratch.mirror(&message).await;
+                    if write_message(&mut stdin, &message).await.is_err() {
+                        break "Language server stopped";
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break "",
+                Some(Ok(_)) => {}
+            },
+            body = server_rx.recv() => match body {
+                Some(body) => {
+                    if sender.send(Message::Text(scratch.to_client(&body))).await.is_err() {
+                        break "";
+                    }
+                }
+                None => break "Language server stopped",
+            },
+            _ = &mut idle => break "Idle timeout",
+        }
+    };
+
+    let frame = CloseFrame { code: 1000, reason: close_reason.into() };
+    let _ = sender.send(Message::Close(Some(frame))).await;
+    let _ = sender.close().await;
+    reader.abort();
+    let _ = child.kill().await;
+    scratch.remove().await;
+}
+
+/// Applies the server's policy to a client message. Returns `false` for
+/// messages that must not reach rust-analyzer.
+fn restrict(message: &mut Value) -> bool {
+    match message.get("method").and_then(Value::as_str) {
+        Some("initialize") => {
+            if let Some(params) = message.get_mut("params").and_then(Value::as_object_mut) {
+                params.insert("initializationOptions".to_string(), settings());
+                // Without this capability rust-analyzer won't ask the client
+                // for settings and sticks to the ones above.
+                if let Some(workspace) = params
+                    .get_mut("capabilities")
+                    .and_then(|c| c.get_mut("workspace"))
+                    .and_then(Value::as_object_mut)
+                {
+                    workspace.insert("configuration".to_string(), json!(false));
+                }
+            }
+            true
+        }
+        Some("workspace/didChangeConfiguration") => false,
+        _ => true,
+    }
+}
+
+/// The per-session Cargo project rust-analyzer works on.
+struct Scratch {
+    dir: PathBuf,
+    /// `dir` as it appears inside URIs.
+    path: String,
+    _hold: gc::Hold,
+}
+
+impl Scratch {
+    async fn create(config: &Config) -> io::Result<Self> {
+        gc::admit(config).await?;
+        let dir = std::path::absolute(config.temp_dir.join(format!("lsp_{}", Uuid::new_v4())))?;
+        let hold = gc::Hold::new(&dir);
+        fs::create_dir_all(dir.join("src")).await?;
+        fs::write(dir.join("Cargo.toml"), CARGO_TOML).await?;
+        fs::write(dir.join("src").join("main.rs"), "fn main() {}\n").await?;
+
+        let path = dir.to_string_lossy().replace('\\', "/");
+        Ok(Self { dir, path, _hold: hold })
+    }
+
+    fn to_server(&self, text: &str) -> String {
+        text.replace(&format!("file://{}", VIRTUAL_ROOT), &format!("file://{}", self.path))
+    }
+
+    fn to_client(&self, text: &str) -> String {
+        text.replace(&self.path, VIRTUAL_ROOT)
+    }
+
+    /// Writes documents the client opens or replaces to disk, so files that
+    /// aren't open (e.g. `mod` declarations resolved from disk) still exist.
+    /// Only `.rs` files under `src/` are mirrored; manifests, build scripts
+    /// and config files stay the server's.
+    async fn mirror(&self, message: &Value) {
+        let params = &message["params"];
+        let text = match message.get("method").and_then(Value::as_str) {
+            Some("textDocument/didOpen") => params["textDocument"]["text"].as_str(),
+            // Only full-document changes, i.e. the last change without a range.
+            Some("textDocument/didChange") => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .filter(|change| change.get("range").is_none())
+                .and_then(|change| change["text"].as_str()),
+            Some("textDocument/didSave") => params["text"].as_str(),
+            _ => None,
+        };
+        let (Some(text), Some(uri)) = (text, params["textDocument"]["uri"].as_str()) else {
+            return;
+        };
+        let Some(relative) = mirrored_path(&self.path, uri) else {
+            return;
+        };
+
+        let path = self.dir.join(relative);
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent).await;
+        }
+        let _ = fs::write(path, text).await;
+    }
+
+    async fn remove(self) {
+        let _ = fs::remove_dir_all(&self.dir).await;
+    }
+}
+
+/// The path relative to the project at `root` that `uri` may be mirrored
+/// to, if any: a `.rs` file under `src/`, named without escapes, `.`, `..`
+/// or anything else that could lead elsewhere.
+fn mirrored_path<'a>(root: &str, uri: &'a str) -> Option<&'a str> {
+    let relative = uri.strip_prefix(&format!("file://{}/", root))?;
+    let allowed = relative.starts_with("src/")
+        && relative.ends_with(".rs")
+        && !relative.contains(['%', '\\'])
+        && relative.split('/').all(|part| !part.is_empty() && part != "." && part != "..");
+    allowed.then_some(relative)
+}
+
+async fn write_message(stdin: &mut ChildStdin, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    stdin
+        .write_all(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes())
+        .await?;
+    stdin.write_all(body.as_bytes()).await?;
+    stdin.flush().await
+}
+
+/// Forwards every `Content-Length` framed message rust-analyzer writes until
+/// it exits or the session ends.
+async fn read_messages<R: AsyncRead + Unpin>(reader: R, messages: mpsc::Sender<String>) {
+    let mut reader = BufReader::new(reader);
+    loop {
+        let mut length = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match reader.read_line(&mut line).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("Content-Length") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+
+        let Some(length) = length else { continue };
+        let mut body = vec![0u8; length];
+        if reader.read_exact(&mut body).await.is_err() {
+            return;
+        }
+        if messages.send(String::from_utf8_lossy(&body).to_string()).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: &str = "/tmp/lsp_1";
+
+    #[test]
+    fn mirrors_rust_files_under_src() {
+        assert_eq!(mirrored_path(ROOT, "file:///tmp/lsp_1/src/main.rs"), Some("src/main.rs"));
+        assert_eq!(mirrored_path(ROOT, "file:///tmp/lsp_1/src/a/b.rs"), Some("src/a/b.rs"));
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        for uri in [
+            "file:///tmp/lsp_1/Cargo.toml",
+            "file:///tmp/lsp_1/build.rs",
+            "file:///tmp/lsp_1/.cargo/config.rs",
+            "file:///tmp/lsp_1/src/notes.txt",
+            "file:///tmp/lsp_1/src",
+        ] {
+            assert_eq!(mirrored_path(ROOT, uri), None, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn stays_inside_the_project() {
+        for uri in [
+            "file:///tmp/lsp_1/src/../build.rs",
+            "file:///tmp/lsp_1/src/../../lsp_2/src/main.rs",
+            "file:///tmp/lsp_1/src/./main.rs",
+            "file:///tmp/lsp_1/src//main.rs",
+            "file:///tmp/lsp_1/src/%2e%2e/build.rs",
+            "file:///tmp/lsp_1/src/..\\..\\main.rs",
+            "file:///tmp/lsp_12/src/main.rs",
+            "file:///tmp/lsp_2/src/main.rs",
+            "file:///etc/src/main.rs",
+            "file:///playground/src/main.rs",
+            "/tmp/lsp_1/src/main.rs",
+        ] {
+            assert_eq!(mirrored_path(ROOT, uri), None, "{}", uri);
+        }
+    }
+}
@@ -1,25 +1,60 @@
 use axum::{
-    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Json},
-    http::Method,
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, DefaultBodyLimit, FromRequest, Json, Multipart, Query, Request, State},
+    http::{header, Method, StatusCode},
+    middleware,
     response::sse::{Event, KeepAlive, Sse},
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Router,
 };
-use futures::{sink::SinkExt, stream::{self, Stream, StreamExt}};
-use serde::{Deserialize, Serialize};
-use std::{convert::Infallible, process::Stdio};
-use tokio::fs;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::process::Command;
-use tokio_util::codec::{FramedRead, LinesCodec};
+use futures::{sink::SinkExt, stream::{Stream, StreamExt}};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::sync::{mpsc, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
 use tower_http::cors::{Any, CorsLayer};
-use uuid::Uuid;
 
+mod artifacts;
 mod db;
 mod auth;
+mod build;
+mod cgroup;
 mod snippets;
 mod docs;
+mod check;
+mod config;
+mod coverage;
+mod diagnostics;
+mod executor;
+mod files;
+mod format;
+mod fuzz;
+mod gc;
+mod health;
+mod history;
+mod keepalive;
+mod lsp;
+mod miri;
+mod mux;
+mod netns;
+mod output;
+mod pty;
+mod quota;
+mod ratelimit;
+mod sanitizer;
+mod session;
+mod spectate;
+mod stall;
+mod usage;
+mod uids;
+mod utf8;
+mod valgrind;
+mod wasm;
+
+use auth::{Claims, OptionalClaims};
+use db::AppState;
+use executor::{ClientMessage, CodeRequest, Input, RunEvent, StdinReceiver};
+use keepalive::{Beat, Keepalive};
+use session::Session;
 
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
@@ -28,22 +63,73 @@ use utoipa_swagger_ui::SwaggerUi;
 async fn main() {
     dotenvy::dotenv().ok();
     let pool = db::init_db().await;
-    let state = db::AppState { db: pool };
+    let mut config = config::Config::from_env();
+    if let Some(root) = &config.cgroup_root {
+        match cgroup::delegate(root) {
+            Ok(()) => println!("Running programs in cgroups under {}", root.display()),
+            Err(e) => {
+                eprintln!("Not using cgroups, {} can't be set up: {}", root.display(), e);
+                config.cgroup_root = None;
+            }
+        }
+    }
+    if config.network_isolation {
+        if let Err(e) = netns::Namespace::create(netns::Network::None).await {
+            eprintln!("Programs will share the server's network, can't create a network namespace: {}", e);
+            config.network_isolation = false;
+        }
+    }
+    if config.run_uids.is_some() {
+        if let Err(e) = uids::setup(&config) {
+            eprintln!("Programs will run as the server's user and can reach each other's files: {}", e);
+            config.run_uids = None;
+        }
+    }
+    let config = Arc::new(config);
+    gc::start(config.clone()).await;
+    let upload_limit = config.upload_limit;
+    let lsp_sessions = Arc::new(Semaphore::new(config.lsp_max_sessions));
+    let rate_limits = ratelimit::RateLimits::new(&config.rate_limits);
+    let state = AppState {
+        db: pool,
+        config,
+        lsp_sessions,
+        checks: Default::default(),
+        sessions: Default::default(),
+        rate_limits,
+    };
+    let limit_runs = middleware::from_fn_with_state(state.clone(), ratelimit::limit_runs);
+    let limit_logins = middleware::from_fn_with_state(state.clone(), ratelimit::limit_logins);
 
     // CORS configuration
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
-        .allow_headers(Any);
+        .allow_headers(Any)
+        // So the frontend can say when to try again after a 429
+        .expose_headers([header::RETRY_AFTER]);
 
     // Build our application with a route
     let app = Router::new()
         .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", docs::ApiDoc::openapi()))
         .route("/", get(|| async { "Rust Compiler API is running!" }))
-        .route("/compile", post(compile_and_run))
-        .route("/ws", get(ws_handler))
-        .route("/auth/register", post(auth::register_handler))
-        .route("/auth/login", post(auth::login_handler))
+        .route("/health", get(health::health_handler))
+        .route("/compile", post(compile_and_run).layer(DefaultBodyLimit::max(upload_limit)).layer(limit_runs.clone()))
+        .route("/check", post(check::check_handler))
+        .route("/format", post(format::format_handler))
+        .route("/targets", get(build::targets_handler))
+        .route("/ws", get(ws_handler).layer(limit_runs))
+        .route("/mux", get(mux::ws_handler))
+        .route("/lsp", get(lsp::ws_handler))
+        .route("/sessions/:id/spectate", post(spectate::share_handler).delete(spectate::revoke_handler))
+        .route("/spectate/:token", get(spectate::spectate_handler))
+        .route("/artifacts/:name", get(artifacts::download))
+        .route("/artifacts/files/:id", get(artifacts::download_file))
+        .route("/auth/register", post(auth::register_handler).layer(limit_logins.clone()))
+        .route("/auth/login", post(auth::login_handler).layer(limit_logins))
+        .route("/me/usage", get(quota::usage_handler))
+        .route("/runs", get(history::list_runs))
+        .route("/runs/:id", get(history::get_run))
         .route("/snippets", post(snippets::create_snippet).get(snippets::list_snippets))
         .route("/snippets/:id", get(snippets::get_snippet)
             .put(snippets::update_snippet)
@@ -55,282 +141,277 @@ async fn main() {
     // Run it
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3001").await.unwrap();
     println!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    // Peer addresses key the rate limits of anonymous clients
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
 
-async fn ws_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
-    ws.on_upgrade(handle_socket)
+/// Query of `/ws` for attaching to a run that is already going.
+#[derive(serde::Deserialize)]
+struct Attach {
+    /// Token of the user to record the run for. Browsers can't set headers
+    /// on a WebSocket, so it comes here or with the first message.
+    token: Option<String>,
+    session_id: Option<String>,
+    /// Number of the run's events the client already has.
+    #[serde(default)]
+    offset: u64,
 }
 
-async fn handle_socket(mut socket: WebSocket) {
-    // 1. Wait for the first message which should be the code
-    let code = if let Some(Ok(msg)) = socket.recv().await {
-        if let Message::Text(text) = msg {
-            // Try to parse as JSON first, or just take raw text if simple
-            if let Ok(req) = serde_json::from_str::<CodeRequest>(&text) {
-                req.code
-            } else {
-                // Fallback if client sends just the code string
-                text
-            }
-        } else {
-            return;
-        }
-    } else {
-        return;
-    };
-
-    let id = Uuid::new_v4();
-    let _ = fs::create_dir_all("temp").await;
-    
-    let filename = format!("temp/temp_{}.rs", id);
-    let exe_name = if cfg!(target_os = "windows") {
-        format!("temp/temp_{}.exe", id)
-    } else {
-        format!("temp/temp_{}", id)
-    };
-    
-    let run_path = if cfg!(target_os = "windows") {
-        format!(".\\temp\\temp_{}.exe", id)
-    } else {
-        format!("./temp/temp_{}", id)
-    };
-
-    // Write code to file
-    if let Err(e) = fs::write(&filename, &code).await {
-        let _ = socket.send(Message::Text(format!("Failed to write file: {}", e))).await;
-        return;
-    }
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(attach): Query<Attach>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let user = attach.token.as_deref().map(auth::verify).transpose()?;
+    Ok(ws.on_upgrade(move |socket| handle_socket(socket, state, attach, user)))
+}
 
-    // Compile
-    let compile_output = Command::new("rustc")
-        .arg(&filename)
-        .arg("-o")
-        .arg(&exe_name)
-        .output()
-        .await;
+/// The first message on `/ws`: the code to run, optionally with the token of
+/// the user to record it for.
+#[derive(serde::Deserialize)]
+struct FirstMessage {
+    token: Option<String>,
+    #[serde(flatten)]
+    request: CodeRequest,
+}
 
-    match compile_output {
-        Ok(output) => {
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-                let _ = socket.send(Message::Text(stderr)).await;
-                let _ = fs::remove_file(&filename).await;
+async fn handle_socket(mut socket: WebSocket, state: AppState, attach: Attach, user: Option<Claims>) {
+    // 1. Find the run to attach to, or wait for the first message, which
+    // should be the code, and start one
+    let (session, offset) = match attach.session_id {
+        Some(id) => match uuid::Uuid::parse_str(&id).ok().and_then(|id| state.sessions.get(id)) {
+            Some(session) => (session, attach.offset),
+            None => {
+                let event = RunEvent::Error { message: "Unknown or expired session".to_string() };
+                let text = serde_json::to_string(&event).expect("run events always serialize");
+                let _ = socket.send(Message::Text(text)).await;
                 return;
             }
+        },
+        None => {
+            let (token, request) = if let Some(Ok(Message::Text(text))) = socket.recv().await {
+                // Try to parse as JSON first, or just take raw text if simple
+                match serde_json::from_str::<FirstMessage>(&text) {
+                    Ok(first) => (first.token, first.request),
+                    // Fallback if client sends just the code string
+                    Err(_) => (None, CodeRequest::from_code(text)),
+                }
+            } else {
+                return;
+            };
+            let user = match token.as_deref().map(auth::verify).transpose() {
+                Ok(claims) => claims.or(user),
+                Err((_, message)) => {
+                    let text = serde_json::to_string(&RunEvent::Error { message }).expect("run events always serialize");
+                    let _ = socket.send(Message::Text(text)).await;
+                    return;
+                }
+            };
+            let account = match &user {
+                Some(claims) => match quota::admit(&state, claims).await {
+                    Ok(account) => Some(account),
+                    Err(refusal) => {
+                        let event = RunEvent::Error { message: refusal.message };
+                        let text = serde_json::to_string(&event).expect("run events always serialize");
+                        let _ = socket.send(Message::Text(text)).await;
+                        return;
+                    }
+                },
+                None => None,
+            };
+            let recorder = user.map(|claims| history::Recorder::new(&state, &claims, &request));
+            (state.sessions.start(state.config.clone(), request, recorder, account), 0)
         }
-        Err(e) => {
-            let _ = socket.send(Message::Text(format!("Failed to execute rustc: {}", e))).await;
-            let _ = fs::remove_file(&filename).await;
-            return;
-        }
-    }
-
-    // Run the executable
-    let mut child = Command::new(&run_path)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn();
-
-    match child {
-        Ok(mut child) => {
-            let mut stdin = child.stdin.take().expect("failed to get stdin");
-            let stdout = child.stdout.take().expect("failed to get stdout");
-            let stderr = child.stderr.take().expect("failed to get stderr");
-
-            let (mut sender, mut receiver) = socket.split();
-
-            // Task to handle stdout/stderr -> WebSocket
-            let mut stdout_reader = BufReader::new(stdout);
-            let mut stderr_reader = BufReader::new(stderr);
-
-            let mut output_task = tokio::spawn(async move {
-                let mut stdout_buf = [0u8; 1024];
-                let mut stderr_buf = [0u8; 1024];
+    };
 
-                loop {
-                    tokio::select! {
-                        result = stdout_reader.read(&mut stdout_buf) => {
-                            match result {
-                                Ok(0) => break, // EOF
-                                Ok(n) => {
-                                    let text = String::from_utf8_lossy(&stdout_buf[..n]).to_string();
-                                    if sender.send(Message::Text(text)).await.is_err() {
-                                        break;
-                                    }
-                                }
-                                Err(_) => break,
-                            }
-                        }
-                        result = stderr_reader.read(&mut stderr_buf) => {
-                            match result {
-                                Ok(0) => break, // EOF
-                                Ok(n) => {
-                                    let text = String::from_utf8_lossy(&stderr_buf[..n]).to_string();
-                                    if sender.send(Message::Text(text)).await.is_err() {
-                                        break;
-                                    }
-                                }
-                                Err(_) => break,
-                            }
-                        }
+    // Whoever attached before is let go; when this client leaves, the run
+    // waits a while for it to come back.
+    let attachment = session.attach();
+    let (mut sender, mut receiver) = socket.split();
+    let keepalive = Keepalive::new(&state.config);
+
+    // Task to handle run events -> WebSocket, and pings
+    let output_session = session.clone();
+    let output_keepalive = keepalive.clone();
+    let mut output_task = tokio::spawn(async move {
+        let keepalive = output_keepalive;
+        let mut events = std::pin::pin!(output_session.clone().follow(offset));
+        loop {
+            tokio::select! {
+                next = events.next() => {
+                    let Some((_, event)) = next else { break };
+                    keepalive.sent();
+                    if sender.send(ws_message(&output_session, event)).await.is_err() {
+                        return;
                     }
                 }
-            });
-
-            // Task to handle WebSocket -> stdin
-            let mut input_task = tokio::spawn(async move {
-                while let Some(Ok(msg)) = receiver.next().await {
-                    if let Message::Text(text) = msg {
-                        // Append newline if missing, as read_line usually expects it
-                        let input = if text.ends_with('\n') { text } else { text + "\n" };
-                        if stdin.write_all(input.as_bytes()).await.is_err() {
-                            break;
-                        }
-                        if stdin.flush().await.is_err() {
-                            break;
-                        }
-                    } else if let Message::Close(_) = msg {
-                        break;
+                beat = keepalive.next() => {
+                    if sender.send(beat.message()).await.is_err() || matches!(beat, Beat::Close(_)) {
+                        return;
                     }
                 }
-            });
-
-            // Wait for child to finish or tasks to end
-            tokio::select! {
-                _ = child.wait() => {},
-                _ = &mut output_task => {},
-                _ = &mut input_task => {},
-            }
-
-            // Cleanup
-            let _ = fs::remove_file(&filename).await;
-            let _ = fs::remove_file(&exe_name).await;
-            if cfg!(target_os = "windows") {
-                let pdb_name = format!("temp/temp_{}.pdb", id);
-                let _ = fs::remove_file(&pdb_name).await;
             }
         }
-        Err(e) => {
-            let _ = socket.send(Message::Text(format!("Failed to spawn process: {}", e))).await;
-            let _ = fs::remove_file(&filename).await;
-            let _ = fs::remove_file(&exe_name).await;
+        let _ = sender.close().await;
+    });
+
+    // Task to handle WebSocket -> stdin
+    let input_session = session.clone();
+    let mut input_task = tokio::spawn(async move {
+        let session = input_session;
+        while let Some(Ok(msg)) = receiver.next().await {
+            keepalive.received(&msg);
+            let input = match msg {
+                Message::Binary(bytes) => Input::Data(bytes),
+                Message::Text(text) => match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(message) => match message.into_input() {
+                        Ok(input) => input,
+                        Err(message) => {
+                            session.push(RunEvent::Error { message });
+                            continue;
+                        }
+                    },
+                    // Terminals only take control messages as text
+                    Err(_) if session.terminal => continue,
+                    Err(_) => {
+                        // Anything else is a line of input. Append newline if
+                        // missing, as read_line usually expects it
+                        let input = if text.ends_with('\n') { text } else { text + "\n" };
+                        Input::Data(input.into_bytes())
+                    }
+                },
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            // Input sent after the program stopped reading is dropped. While
+            // the buffer is full this waits, and the client is read no further.
+            let _ = session.send_input(input).await;
+        }
+    });
+
+    // The run goes on without a client for a grace period; dropping the
+    // attachment starts it.
+    tokio::select! {
+        _ = &mut output_task => input_task.abort(),
+        _ = &mut input_task => output_task.abort(),
+        _ = attachment.token.cancelled() => {
+            input_task.abort();
+            output_task.abort();
         }
     }
 }
 
-#[derive(Deserialize)]
-struct CodeRequest {
-    code: String,
+/// Frames a run event for a session's client. Terminal output goes out as
+/// binary frames, ready for xterm.js. Raw output does too when asked for,
+/// behind a stream byte.
+fn ws_message(session: &Session, event: RunEvent) -> Message {
+    match event {
+        RunEvent::Terminal { data } => Message::Binary(data),
+        RunEvent::Output { stream, data } if session.binary => {
+            let channel = match stream {
+                executor::Stream::Stdout => 1,
+                executor::Stream::Stderr => 2,
+            };
+            Message::Binary([&[channel], &data[..]].concat())
+        }
+        event => Message::Text(serde_json::to_string(&event).expect("run events always serialize")),
+    }
 }
 
+// POST /compile takes either a JSON `CodeRequest`, or multipart/form-data with
+// the request as a `request` part followed by a `stdin` file part.
 async fn compile_and_run(
-    Json(payload): Json<CodeRequest>,
-) -> Sse<std::pin::Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>>> {
-    let code = payload.code;
-    let id = Uuid::new_v4();
-    let _ = fs::create_dir_all("temp").await;
-    
-    let filename = format!("temp/temp_{}.rs", id);
-    let exe_name = if cfg!(target_os = "windows") {
-        format!("temp/temp_{}.exe", id)
-    } else {
-        format!("temp/temp_{}", id)
-    };
-    
-    // For running, we need the path realtive to current dir or absolute.
-    let run_path = if cfg!(target_os = "windows") {
-        format!(".\\temp\\temp_{}.exe", id)
+    State(state): State<AppState>,
+    OptionalClaims(user): OptionalClaims,
+    request: Request,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Response> {
+    let multipart = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+
+    let (payload, stdin) = if multipart {
+        let multipart = Multipart::from_request(request, &state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let (payload, stdin) = upload_request(multipart, state.config.stdin_buffer_limit)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        (payload, Some(stdin))
     } else {
-        format!("./temp/temp_{}", id)
+        let Json(payload) = Json::<CodeRequest>::from_request(request, &state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        (payload, None)
     };
 
-    // Write code to file
-    if let Err(e) = fs::write(&filename, &code).await {
-        let stream = stream::once(async move {
-            Ok(Event::default().data(format!("Failed to write file: {}", e)))
-        });
-        return Sse::new(Box::pin(stream) as std::pin::Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>>).keep_alive(KeepAlive::default());
-    }
+    let account = match &user {
+        Some(claims) => Some(quota::admit(&state, claims).await.map_err(IntoResponse::into_response)?),
+        None => None,
+    };
+    let (event_tx, event_rx) = mpsc::channel::<RunEvent>(64);
+    let event_rx = match user {
+        Some(claims) => history::Recorder::new(&state, &claims, &payload).tee(event_rx),
+        None => event_rx,
+    };
+    tokio::spawn(executor::execute(state.config.clone(), payload, event_tx, stdin, account));
 
-    // Compile
-    let compile_output = Command::new("rustc")
-        .arg(&filename)
-        .arg("-o")
-        .arg(&exe_name)
-        .output()
-        .await;
+    let stream = ReceiverStream::new(event_rx).map(|event| Ok(sse_event(event)));
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
 
-    match compile_output {
-        Ok(output) => {
-            if !output.status.success() {
-                // Compilation failed
-                let _ = fs::remove_file(&filename).await;
-                let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-                let stream = stream::once(async move {
-                    Ok(Event::default().data(stderr))
-                });
-                return Sse::new(Box::pin(stream) as std::pin::Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>>).keep_alive(KeepAlive::default());
+// Reads the `request` part and hands the rest of the upload to a task that
+// streams the `stdin` part into the program as it reads, then closes stdin.
+async fn upload_request(
+    mut multipart: Multipart,
+    buffer_limit: usize,
+) -> Result<(CodeRequest, StdinReceiver), (StatusCode, String)> {
+    let bad_request = |message: String| (StatusCode::BAD_REQUEST, message);
+
+    let field = multipart
+        .next_field()
+        .await
+        .map_err(|e| bad_request(e.body_text()))?
+        .ok_or_else(|| bad_request("Missing `request` part".to_string()))?;
+    if field.name() != Some("request") {
+        return Err(bad_request("The first part must be `request`".to_string()));
+    }
+    let bytes = field.bytes().await.map_err(|e| bad_request(e.body_text()))?;
+    let payload: CodeRequest = serde_json::from_slice(&bytes).map_err(|e| bad_request(e.to_string()))?;
+
+    let (stdin_tx, stdin_rx) = executor::stdin_channel(buffer_limit);
+    tokio::spawn(async move {
+        while let Ok(Some(mut field)) = multipart.next_field().await {
+            if field.name() != Some("stdin") {
+                continue;
+            }
+            while let Ok(Some(chunk)) = field.chunk().await {
+                if stdin_tx.send(Input::Data(chunk.to_vec())).await.is_err() {
+                    return;
+                }
             }
         }
-        Err(e) => {
-            let _ = fs::remove_file(&filename).await;
-            let stream = stream::once(async move {
-                Ok(Event::default().data(format!("Failed to execute rustc: {}", e)))
-            });
-            return Sse::new(Box::pin(stream) as std::pin::Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>>).keep_alive(KeepAlive::default());
-        }
-    }
+        let _ = stdin_tx.send(Input::Eof).await;
+    });
 
-    // Run the executable
-    let mut child = Command::new(&run_path)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn();
-
-    match child {
-        Ok(mut child) => {
-            let stdout = child.stdout.take().expect("child did not have a handle to stdout");
-            let stderr = child.stderr.take().expect("child did not have a handle to stderr");
-
-            let stdout_stream = FramedRead::new(stdout, LinesCodec::new())
-                .map(|line| line.map_err(|e| e.to_string()));
-            let stderr_stream = FramedRead::new(stderr, LinesCodec::new())
-                .map(|line| line.map_err(|e| e.to_string()));
-
-            let merged_stream = stream::select(stdout_stream, stderr_stream)
-                .map(|result| {
-                    match result {
-                        Ok(line) => Ok(Event::default().data(line)),
-                        Err(e) => Ok(Event::default().data(format!("Error reading output: {}", e))),
-                    }
-                });
-
-            // Cleanup task
-            tokio::spawn(async move {
-                let _ = child.wait().await;
-                let _ = fs::remove_file(&filename).await;
-                let _ = fs::remove_file(&exe_name).await;
-                if cfg!(target_os = "windows") {
-                    let pdb_name = format!("temp/temp_{}.pdb", id);
-                    let _ = fs::remove_file(&pdb_name).await;
-                }
-            });
+    Ok((payload, stdin_rx))
+}
 
-            Sse::new(Box::pin(merged_stream) as std::pin::Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>>).keep_alive(KeepAlive::default())
-        }
-        Err(e) => {
-            let _ = fs::remove_file(&filename).await;
-            let _ = fs::remove_file(&exe_name).await;
-            if cfg!(target_os = "windows") {
-                let pdb_name = format!("temp/temp_{}.pdb", id);
-                let _ = fs::remove_file(&pdb_name).await;
-            }
-            let stream = stream::once(async move {
-                Ok(Event::default().data(format!("Failed to spawn process: {}", e)))
-            });
-            return Sse::new(Box::pin(stream) as std::pin::Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>>).keep_alive(KeepAlive::default());
+// Program output goes out as plain `data:` lines so simple clients can just
+// print them; everything else is a named event carrying JSON.
+fn sse_event(event: RunEvent) -> Event {
+    match event {
+        // Each event is one chunk of output, partial lines included; the
+        // client gets it back by joining the `data:` lines with newlines.
+        // SSE has no way to carry a carriage return, so those become line
+        // breaks, as an EventSource would read them anyway.
+        RunEvent::Stdout { data } | RunEvent::Stderr { data } | RunEvent::CompileError { data } => {
+            Event::default().data(data.replace("\r\n", "\n").replace('\r', "\n"))
         }
+        event => Event::default()
+            .event(event.name())
+            .json_data(&event)
+            .unwrap_or_default(),
     }
 }
@@ -0,0 +1,43 @@
+use std::{io, path::{Path, PathBuf}};
+use tokio::process::Command;
+use tokio::sync::OnceCell;
+
+use crate::{config::Config, executor};
+
+static SYSROOT: OnceCell<PathBuf> = OnceCell::const_new();
+
+/// Builds the command that interprets `source` under Miri. Miri needs its own
+/// sysroot, which `cargo miri setup` builds on first use; we do that once per
+/// process and reuse the path afterwards.
+pub async fn command(config: &Config, source: &Path) -> io::Result<Command> {
+    let sysroot = SYSROOT.get_or_try_init(|| setup(config)).await?;
+
+    let mut cmd = executor::nightly(config, "miri");
+    cmd.arg("--sysroot")
+        .arg(sysroot)
+        .arg("--error-format=json")
+        .arg(source);
+    Ok(cmd)
+}
+
+async fn setup(config: &Config) -> io::Result<PathBuf> {
+    let output = Command::new("cargo")
+        .arg(format!("+{}", config.nightly_toolchain))
+        .args(["miri", "setup", "--print-sysroot"])
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "cargo miri setup failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .last()
+        .map(|line| PathBuf::from(line.trim()))
+        .ok_or_else(|| io::Error::other("cargo miri setup printed no sysroot"))
+}
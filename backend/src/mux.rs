@@ -0,0 +1,374 @@
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, ConnectInfo, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use futures::{sink::SinkExt, stream::StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    auth::{self, Claims},
+    check::{self, CheckResponse},
+    db::AppState,
+    executor::{ClientMessage, CodeRequest, Input, RunEvent},
+    format::{self, FormatRequest, FormatResponse},
+    history::Recorder,
+    keepalive::{Beat, Keepalive},
+    quota::{self, Refusal},
+    ratelimit::{self, Client},
+    session::Session,
+};
+
+/// A message from the client about the run or request `id`, which the
+/// client picks.
+#[derive(Deserialize)]
+struct Envelope {
+    id: String,
+    #[serde(flatten)]
+    body: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Start { request: Box<CodeRequest> },
+    /// Follows a run started earlier, as reconnecting to `/ws` does.
+    Attach {
+        session_id: String,
+        #[serde(default)]
+        offset: u64,
+    },
+    /// Stops a run, check or format.
+    Cancel,
+    Check { code: String },
+    Format(FormatRequest),
+}
+
+/// A message to the client: one of a run's events, or an answer about `id`.
+#[derive(Serialize)]
+struct Reply<'a, T> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    #[serde(flatten)]
+    body: T,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Answer {
+    Check(CheckResponse),
+    Format(FormatResponse),
+    /// The check or format was cancelled.
+    Cancelled,
+    /// The run's events are over; nothing more comes for its id.
+    End,
+    /// The run wasn't started, the client started too many lately.
+    RateLimited { retry_after: u64 },
+    /// The run wasn't started, one of the user's daily quotas is used up.
+    QuotaExceeded { message: String, retry_after: u64 },
+    Error { message: String },
+}
+
+fn reply<T: Serialize>(id: Option<&str>, body: T) -> String {
+    serde_json::to_string(&Reply { id, body }).expect("replies always serialize")
+}
+
+/// Input messages queued for a run, ahead of its stdin buffer.
+const INPUT_QUEUE: usize = 16;
+
+/// A run or request of the connection.
+struct Task {
+    /// Tells apart tasks that reused an id.
+    number: u64,
+    /// Set for runs.
+    session: Option<Arc<Session>>,
+    /// Input for the run, passed on to it in order by a task of its own.
+    input: Option<mpsc::Sender<Input>>,
+    /// Stops following a run, or ends a request.
+    stop: CancellationToken,
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.stop.cancel();
+    }
+}
+
+struct Mux {
+    state: AppState,
+    /// Whose history runs started here go into.
+    user: Option<Claims>,
+    /// Who runs started here count against.
+    client: Client,
+    out: mpsc::Sender<String>,
+    tasks: HashMap<String, Task>,
+    finished: mpsc::UnboundedSender<(String, u64)>,
+    started: u64,
+}
+
+#[derive(Deserialize)]
+pub struct Auth {
+    /// Token of the user to record runs for.
+    token: Option<String>,
+}
+
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<Auth>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let user = query.token.as_deref().map(auth::verify).transpose()?;
+    let client = match &user {
+        Some(claims) => Client::User(claims.sub.clone()),
+        None => Client::Anonymous(addr.ip()),
+    };
+    Ok(ws.on_upgrade(move |socket| handle_socket(socket, state, user, client)))
+}
+
+async fn handle_socket(socket: WebSocket, state: AppState, user: Option<Claims>, client: Client) {
+    let (mut sender, mut receiver) = socket.split();
+    let (out, mut outgoing) = mpsc::channel::<String>(64);
+    let (finished, mut done) = mpsc::unbounded_channel();
+    let keepalive = Keepalive::new(&state.config);
+    let beats = keepalive.clone();
+    let mut writer = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                text = outgoing.recv() => {
+                    let Some(text) = text else { break };
+                    beats.sent();
+                    if sender.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                beat = beats.next() => {
+                    if sender.send(beat.message()).await.is_err() || matches!(beat, Beat::Close(_)) {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut mux = Mux { state, user, client, out, tasks: HashMap::new(), finished, started: 0 };
+    loop {
+        tokio::select! {
+            message = receiver.next() => {
+                let Some(Ok(message)) = message else { break };
+                keepalive.received(&message);
+                match message {
+                    Message::Text(text) => mux.handle(&text).await,
+                    Message::Binary(_) => {
+                        let message = "Binary frames aren't taken here, send `binary` messages".to_string();
+                        mux.send(None, Answer::Error { message }).await;
+                    }
+                    Message::Close(_) => break,
+                    _ => {}
+                }
+            }
+            Some((id, number)) = done.recv() => {
+                if mux.tasks.get(&id).is_some_and(|task| task.number == number) {
+                    mux.tasks.remove(&id);
+                }
+            }
+            // Closed for want of pongs or messages
+            _ = &mut writer => break,
+        }
+    }
+    // Runs carry on without the connection for a grace period, as on `/ws`;
+    // checks and formats stop.
+    drop(mux);
+    writer.abort();
+}
+
+impl Mux {
+    async fn send(&self, id: Option<&str>, answer: Answer) {
+        let _ = self.out.send(reply(id, answer)).await;
+    }
+
+    async fn handle(&mut self, text: &str) {
+        let envelope: Envelope = match serde_json::from_str(text) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                let message = format!("Invalid message: {}", e);
+                return self.send(None, Answer::Error { message }).await;
+            }
+        };
+        let id = envelope.id;
+        let request = match serde_json::from_value::<Request>(envelope.body.clone()) {
+            Ok(request) => request,
+            Err(error) => match serde_json::from_value::<ClientMessage>(envelope.body) {
+                Ok(message) => return self.input(&id, message).await,
+                Err(_) => {
+                    let message = format!("Invalid message: {}", error);
+                    return self.send(Some(&id), Answer::Error { message }).await;
+                }
+            },
+        };
+
+        if let Request::Cancel = request {
+            match self.tasks.get(&id) {
+                Some(Task { session: Some(session), .. }) => session.cancel(),
+                Some(task) => task.stop.cancel(),
+                None => self.send(Some(&id), Answer::Error { message: "Nothing to cancel with this id".to_string() }).await,
+            }
+            return;
+        }
+        if self.tasks.contains_key(&id) {
+            let message = "A run or request with this id is still going".to_string();
+            return self.send(Some(&id), Answer::Error { message }).await;
+        }
+
+        self.started += 1;
+        let number = self.started;
+        let stop = CancellationToken::new();
+        let mut session = None;
+        match request {
+            Request::Start { request } => {
+                // Runs count against the rate limits one by one, as on `/ws`
+                if let Err(wait) = self.state.rate_limits.run(&self.client) {
+                    let retry_after = ratelimit::retry_after(wait);
+                    return self.send(Some(&id), Answer::RateLimited { retry_after }).await;
+                }
+                let account = match &self.user {
+                    Some(claims) => match quota::admit(&self.state, claims).await {
+                        Ok(account) => Some(account),
+                        Err(Refusal { message, retry_after, .. }) => {
+                            let answer = match retry_after {
+                                Some(retry_after) => Answer::QuotaExceeded { message, retry_after },
+                                None => Answer::Error { message },
+                            };
+                            return self.send(Some(&id), answer).await;
+                        }
+                    },
+                    None => None,
+                };
+                let recorder = self.user.as_ref().map(|claims| Recorder::new(&self.state, claims, &request));
+                let started = self.state.sessions.start(self.state.config.clone(), *request, recorder, account);
+                session = Some(started.clone());
+                self.follow(&id, number, started, 0, stop.clone());
+            }
+            Request::Attach { session_id, offset } => {
+                let Some(attached) = Uuid::parse_str(&session_id).ok().and_then(|id| self.state.sessions.get(id)) else {
+                    let message = "Unknown or expired session".to_string();
+                    return self.send(Some(&id), Answer::Error { message }).await;
+                };
+                session = Some(attached.clone());
+                self.follow(&id, number, attached, offset, stop.clone());
+            }
+            Request::Check { code } => {
+                let config = self.state.config.clone();
+                let cancel = stop.clone();
+                self.spawn(&id, number, async move {
+                    match check::check(config, code, cancel).await {
+                        Ok(Some(response)) => Answer::Check(response),
+                        Ok(None) => Answer::Cancelled,
+                        Err((_, message)) => Answer::Error { message },
+                    }
+                });
+            }
+            Request::Format(request) => {
+                let config = self.state.config.clone();
+                let cancel = stop.clone();
+                self.spawn(&id, number, async move {
+                    match format::format(&config, request, cancel).await {
+                        Ok(Some(response)) => Answer::Format(response),
+                        Ok(None) => Answer::Cancelled,
+                        Err((_, message)) => Answer::Error { message },
+                    }
+                });
+            }
+            Request::Cancel => unreachable!("handled above"),
+        }
+        let input = session.clone().map(|session| forward_input(session, stop.clone()));
+        self.tasks.insert(id, Task { number, session, input, stop });
+    }
+
+    /// Queues input for the run `id`. The connection's other runs and
+    /// requests carry on while this run's stdin buffer is full; once its
+    /// queue is full too, input is dropped and the client told.
+    async fn input(&self, id: &str, message: ClientMessage) {
+        let Some(Task { input: Some(queue), .. }) = self.tasks.get(id) else {
+            return self.send(Some(id), Answer::Error { message: "No run with this id".to_string() }).await;
+        };
+        match message.into_input() {
+            Ok(input) => match queue.try_send(input) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    let message = "Input dropped, the program isn't reading it as fast as it comes".to_string();
+                    self.send(Some(id), Answer::Error { message }).await;
+                }
+                // Input sent after the program stopped reading is dropped.
+                Err(mpsc::error::TrySendError::Closed(_)) => {}
+            },
+            Err(message) => self.send(Some(id), Answer::Error { message }).await,
+        }
+    }
+
+    /// Sends the run's events from `offset` on under `id`, then `end`.
+    fn follow(&self, id: &str, number: u64, session: Arc<Session>, offset: u64, stop: CancellationToken) {
+        let attachment = session.attach();
+        let (id, out, finished) = (id.to_string(), self.out.clone(), self.finished.clone());
+        tokio::spawn(async move {
+            let mut events = std::pin::pin!(session.clone().follow(offset));
+            loop {
+                let event = tokio::select! {
+                    next = events.next() => match next {
+                        Some((_, event)) => event,
+                        None => break,
+                    },
+                    _ = attachment.token.cancelled() => RunEvent::Error {
+                        message: "Attached from another connection".to_string(),
+                    },
+                    _ = stop.cancelled() => return,
+                };
+                let taken_over = attachment.token.is_cancelled();
+                if out.send(reply(Some(&id), event)).await.is_err() {
+                    return;
+                }
+                if taken_over {
+                    break;
+                }
+            }
+            let _ = out.send(reply(Some(&id), Answer::End)).await;
+            let _ = finished.send((id, number));
+        });
+    }
+
+    /// Runs a check or format and sends its answer under `id`.
+    fn spawn(&self, id: &str, number: u64, task: impl std::future::Future<Output = Answer> + Send + 'static) {
+        let (id, out, finished) = (id.to_string(), self.out.clone(), self.finished.clone());
+        tokio::spawn(async move {
+            let answer = task.await;
+            let _ = out.send(reply(Some(&id), answer)).await;
+            let _ = finished.send((id, number));
+        });
+    }
+}
+
+/// Passes queued input on to the run, waiting while its stdin buffer is full,
+/// until the program stops reading or the task ends.
+fn forward_input(session: Arc<Session>, stop: CancellationToken) -> mpsc::Sender<Input> {
+    let (queue, mut queued) = mpsc::channel(INPUT_QUEUE);
+    tokio::spawn(async move {
+        loop {
+            let input = tokio::select! {
+                input = queued.recv() => match input {
+                    Some(input) => input,
+                    None => break,
+                },
+                _ = stop.cancelled() => break,
+            };
+            tokio::select! {
+                sent = session.send_input(input) => if sent.is_err() { break },
+                _ = stop.cancelled() => break,
+            }
+        }
+    });
+    queue
+}
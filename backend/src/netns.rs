@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio::process::Command;
+
+/// Network a run's program gets.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Network {
+    /// No interfaces at all, not even loopback.
+    #[default]
+    None,
+    /// Only `lo`, private to the run, e.g. for a TCP server and client
+    /// talking over 127.0.0.1.
+    Loopback,
+}
+
+/// How a run's network was sandboxed.
+#[derive(Serialize, Clone, Debug)]
+pub struct Report {
+    pub network: Network,
+    /// Connection attempts (and datagrams) that failed for lack of a route,
+    /// i.e. tried to leave the sandbox.
+    pub blocked_connections: u64,
+}
+
+/// A fresh network namespace for one run. It lives as long as this does, so
+/// its counters can still be read after the program exited.
+pub struct Namespace {
+    network: Network,
+    inner: imp::Namespace,
+}
+
+impl Namespace {
+    /// Needs `CAP_SYS_ADMIN`.
+    pub async fn create(network: Network) -> io::Result<Self> {
+        // Entering a namespace changes the calling thread for good, so do it
+        // on a thread of its own that ends right after.
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(imp::Namespace::create(network == Network::Loopback));
+        });
+        let inner = rx.await.map_err(io::Error::other)??;
+        Ok(Self { network, inner })
+    }
+
+    /// Makes `command` start inside the namespace.
+    pub fn join(&self, command: &mut Command) {
+        self.inner.join(command)
+    }
+
+    pub fn report(&self) -> Report {
+        Report { network: self.network, blocked_connections: self.inner.no_routes() }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{
+        fs::File,
+        io::{self, Read, Seek},
+        mem,
+        os::fd::{AsRawFd, OwnedFd},
+    };
+    use tokio::process::Command;
+
+    pub struct Namespace {
+        ns: OwnedFd,
+        // Opened from inside, so they keep reporting on this namespace.
+        snmp: File,
+        snmp6: Option<File>,
+    }
+
+    impl Namespace {
+        /// Moves the calling thread into a new network namespace.
+        pub fn create(loopback: bool) -> io::Result<Self> {
+            // SAFETY: plain syscall.
+            if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ns = File::open("/proc/thread-self/ns/net")?.into();
+            let snmp = File::open("/proc/thread-self/net/snmp")?;
+            let snmp6 = File::open("/proc/thread-self/net/snmp6").ok();
+            if loopback {
+                loopback_up()?;
+            }
+            Ok(Self { ns, snmp, snmp6 })
+        }
+
+        pub fn join(&self, command: &mut Command) {
+            let ns = self.ns.as_raw_fd();
+            // SAFETY: only setns between fork and exec; `ns` stays open
+            // for as long as `self` and is inherited by the child.
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::setns(ns, libc::CLONE_NEWNET) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+
+        /// `OutNoRoutes` for IPv4 and IPv6. A TCP connect or UDP send that
+        /// finds no route counts there before failing with `ENETUNREACH`.
+        pub fn no_routes(&self) -> u64 {
+            let ipv4 = read(&self.snmp)
+                .and_then(|snmp| {
+                    // A header line of names, then one of values.
+                    let mut ip = snmp.lines().filter(|line| line.starts_with("Ip: "));
+                    let (names, values) = (ip.next()?, ip.next()?);
+                    let index = names.split_whitespace().position(|name| name == "OutNoRoutes")?;
+                    values.split_whitespace().nth(index)?.parse().ok()
+                })
+                .unwrap_or(0);
+            let ipv6 = self
+                .snmp6
+                .as_ref()
+                .and_then(read)
+                .and_then(|snmp6| {
+                    let line = snmp6.lines().find(|line| line.starts_with("Ip6OutNoRoutes"))?;
+                    line.split_whitespace().nth(1)?.parse().ok()
+                })
+                .unwrap_or(0);
+            ipv4 + ipv6
+        }
+    }
+
+    fn read(mut file: &File) -> Option<String> {
+        file.rewind().ok()?;
+        let mut text = String::new();
+        file.read_to_string(&mut text).ok()?;
+        Some(text)
+    }
+
+    /// Brings up `lo` in the calling thread's namespace.
+    fn loopback_up() -> io::Result<()> {
+        // SAFETY: plain syscall.
+        let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if socket == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: socket returned a descriptor we own.
+        let socket: OwnedFd = unsafe { std::os::fd::FromRawFd::from_raw_fd(socket) };
+
+        // SAFETY: ifreq is plain data, all zeroes is a valid value.
+        let mut request: libc::ifreq = unsafe { mem::zeroed() };
+        for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+            *dst = *src as libc::c_char;
+        }
+        request.ifr_ifru.ifru_flags = libc::IFF_UP as libc::c_short;
+        // SAFETY: SIOCSIFFLAGS reads an ifreq from the pointer, which is valid.
+        if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, &request) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+    use tokio::process::Command;
+
+    pub struct Namespace;
+
+    impl Namespace {
+        pub fn create(_loopback: bool) -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "network namespaces need a Linux host"))
+        }
+
+        pub fn join(&self, _command: &mut Command) {}
+
+        pub fn no_routes(&self) -> u64 {
+            0
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use serde::Serialize;
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+/// What happens to a run once its output goes over a limit.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LimitAction {
+    /// Stop the program; anything it already wrote is discarded.
+    Kill,
+    /// Let the program run on, discarding output while over the limit.
+    Drop,
+}
+
+impl FromStr for LimitAction {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, ()> {
+        match value {
+            "kill" => Ok(LimitAction::Kill),
+            "drop" => Ok(LimitAction::Drop),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Caps on the output of a single run, stdout and stderr combined.
+#[derive(Clone, Copy, Debug)]
+pub struct OutputLimits {
+    /// Most bytes forwarded to the client over the whole run.
+    pub max_bytes: u64,
+    /// Sustained bytes per second, with bursts of up to a second's worth.
+    /// `0` means unlimited.
+    pub rate: u64,
+    pub action: LimitAction,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Limit {
+    Total,
+    Rate,
+}
+
+/// Reported once a run's output has been cut short.
+#[derive(Serialize, Clone, Debug)]
+pub struct Truncation {
+    /// The first limit that was hit.
+    pub limit: Limit,
+    /// Output the program wrote that never reached the client.
+    pub discarded_bytes: u64,
+    /// The program was stopped because of it.
+    pub killed: bool,
+}
+
+/// Applies `OutputLimits` to everything read from one run's output streams.
+/// Clones share the same budget.
+#[derive(Clone)]
+pub struct Limiter {
+    limits: OutputLimits,
+    state: Arc<Mutex<State>>,
+    exceeded: CancellationToken,
+}
+
+struct State {
+    forwarded: u64,
+    discarded: u64,
+    /// Token bucket for the rate limit, in bytes.
+    budget: f64,
+    refilled: Instant,
+    hit: Option<Limit>,
+}
+
+impl Limiter {
+    pub fn new(limits: OutputLimits) -> Self {
+        let state = State {
+            forwarded: 0,
+            discarded: 0,
+            budget: limits.rate as f64,
+            refilled: Instant::now(),
+            hit: None,
+        };
+        Self { limits, state: Arc::new(Mutex::new(state)), exceeded: CancellationToken::new() }
+    }
+
+    /// Takes `len` freshly read bytes into account and returns how many of
+    /// them may be forwarded; the rest is discarded.
+    pub fn admit(&self, len: usize) -> usize {
+        self.admit_at(len, Instant::now())
+    }
+
+    fn admit_at(&self, len: usize, now: Instant) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let len = len as u64;
+        if self.exceeded.is_cancelled() {
+            state.discarded += len;
+            return 0;
+        }
+
+        let mut allowed = len.min(self.limits.max_bytes.saturating_sub(state.forwarded));
+        let mut hit = (allowed < len).then_some(Limit::Total);
+        if self.limits.rate > 0 {
+            let refill = now.duration_since(state.refilled).as_secs_f64() * self.limits.rate as f64;
+            state.budget = (state.budget + refill).min(self.limits.rate as f64);
+            state.refilled = now;
+            if allowed as f64 > state.budget {
+                allowed = state.budget as u64;
+                hit = hit.or(Some(Limit::Rate));
+            }
+            state.budget -= allowed as f64;
+        }
+
+        state.forwarded += allowed;
+        state.discarded += len - allowed;
+        if let Some(limit) = hit {
+            state.hit.get_or_insert(limit);
+            if self.limits.action == LimitAction::Kill {
+                self.exceeded.cancel();
+            }
+        }
+        allowed as usize
+    }
+
+    /// Completes once the program has to be stopped for its output.
+    pub async fn exceeded(&self) {
+        self.exceeded.cancelled().await
+    }
+
+    /// What was cut, if anything.
+    pub fn truncation(&self) -> Option<Truncation> {
+        let state = self.state.lock().unwrap();
+        state.hit.map(|limit| Truncation {
+            limit,
+            discarded_bytes: state.discarded,
+            killed: self.exceeded.is_cancelled(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limiter(max_bytes: u64, rate: u64, action: LimitAction) -> Limiter {
+        Limiter::new(OutputLimits { max_bytes, rate, action })
+    }
+
+    #[test]
+    fn forwards_everything_under_the_limits() {
+        let limiter = limiter(100, 0, LimitAction::Kill);
+        assert_eq!(limiter.admit(60), 60);
+        assert_eq!(limiter.admit(40), 40);
+        assert!(limiter.truncation().is_none());
+    }
+
+    #[test]
+    fn kills_at_the_total_and_discards_the_rest() {
+        let limiter = limiter(100, 0, LimitAction::Kill);
+        assert_eq!(limiter.admit(60), 60);
+        assert_eq!(limiter.admit(60), 40);
+        assert!(limiter.exceeded.is_cancelled());
+        // Anything still coming in after the kill is dropped.
+        assert_eq!(limiter.admit(10), 0);
+        let truncation = limiter.truncation().unwrap();
+        assert_eq!((truncation.limit, truncation.discarded_bytes, truncation.killed), (Limit::Total, 30, true));
+    }
+
+    #[test]
+    fn drops_over_the_total_without_killing() {
+        let limiter = limiter(10, 0, LimitAction::Drop);
+        assert_eq!(limiter.admit(15), 10);
+        assert_eq!(limiter.admit(5), 0);
+        assert!(!limiter.exceeded.is_cancelled());
+        let truncation = limiter.truncation().unwrap();
+        assert_eq!((truncation.limit, truncation.discarded_bytes, truncation.killed), (Limit::Total, 10, false));
+    }
+
+    #[test]
+    fn refills_the_rate_budget_up_to_a_second_worth() {
+        let limiter = limiter(u64::MAX, 1000, LimitAction::Drop);
+        let start = limiter.state.lock().unwrap().refilled;
+        // A second's worth goes out at once, no more.
+        assert_eq!(limiter.admit_at(1500, start), 1000);
+        assert_eq!(limiter.admit_at(10, start), 0);
+        // Refilled at 1000 bytes a second
+        assert_eq!(limiter.admit_at(500, start + Duration::from_millis(250)), 250);
+        // However long it waits, the budget stays a second's worth.
+        assert_eq!(limiter.admit_at(5000, start + Duration::from_secs(60)), 1000);
+        let truncation = limiter.truncation().unwrap();
+        assert_eq!((truncation.limit, truncation.discarded_bytes), (Limit::Rate, 500 + 10 + 250 + 4000));
+    }
+
+    #[test]
+    fn reports_the_first_limit_hit() {
+        let limiter = limiter(100, 50, LimitAction::Drop);
+        let start = limiter.state.lock().unwrap().refilled;
+        assert_eq!(limiter.admit_at(80, start), 50);
+        assert_eq!(limiter.admit_at(80, start + Duration::from_secs(10)), 50);
+        assert_eq!(limiter.truncation().unwrap().limit, Limit::Rate);
+    }
+}
@@ -0,0 +1,203 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio::process::Command;
+
+use crate::usage::Reaper;
+
+pub use imp::Pty;
+
+/// Size of the client's terminal, in character cells.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct TerminalSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Default for TerminalSize {
+    fn default() -> Self {
+        Self { cols: 80, rows: 24 }
+    }
+}
+
+/// Spawns `command` with a new pseudo-terminal of the given size as its
+/// stdio and controlling terminal.
+pub fn spawn(command: &mut Command, size: TerminalSize) -> io::Result<(Reaper, Pty)> {
+    imp::spawn(command, size)
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::TerminalSize;
+    use crate::usage::{self, Reaper};
+    use std::{
+        fs::File,
+        io::{self, Read, Write},
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        pin::Pin,
+        process::Stdio,
+        ptr,
+        task::{ready, Context, Poll},
+    };
+    use tokio::{
+        io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf},
+        process::Command,
+    };
+
+    /// The master side of a pseudo-terminal.
+    pub struct Pty {
+        master: OwnedFd,
+    }
+
+    impl Pty {
+        pub fn resize(&self, size: TerminalSize) -> io::Result<()> {
+            let winsize = winsize(size);
+            // SAFETY: TIOCSWINSZ reads a winsize from the pointer, which is valid.
+            if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// A handle for reading the terminal output. Reads fail once the
+        /// program and everything it started have exited.
+        pub fn reader(&self) -> io::Result<Master> {
+            Ok(Master(AsyncFd::new(File::from(self.master.try_clone()?))?))
+        }
+
+        /// A handle for typing into the terminal.
+        pub fn writer(&self) -> io::Result<Master> {
+            self.reader()
+        }
+    }
+
+    /// A copy of the master side driven by the runtime's reactor rather than
+    /// the blocking pool, so a read still waiting when it is dropped (say,
+    /// for a grandchild that holds the terminal open) goes with it.
+    pub struct Master(AsyncFd<File>);
+
+    impl AsyncRead for Master {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            loop {
+                let mut guard = ready!(self.0.poll_read_ready(cx))?;
+                match guard.try_io(|file| file.get_ref().read(buf.initialize_unfilled())) {
+                    Ok(read) => {
+                        buf.advance(read?);
+                        return Poll::Ready(Ok(()));
+                    }
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for Master {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+            loop {
+                let mut guard = ready!(self.0.poll_write_ready(cx))?;
+                match guard.try_io(|file| file.get_ref().write(data)) {
+                    Ok(written) => return Poll::Ready(written),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    pub fn spawn(command: &mut Command, size: TerminalSize) -> io::Result<(Reaper, Pty)> {
+        let (mut master, mut slave) = (-1, -1);
+        let winsize = winsize(size);
+        // SAFETY: both out-pointers are valid, and a null name/termios is allowed.
+        if unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), &winsize) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: openpty succeeded, so both are open descriptors we now own.
+        let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        for fd in [&master, &slave] {
+            // Neither may leak into the child beyond its stdio.
+            // SAFETY: fd is a valid open descriptor.
+            unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        // Copies share the flag, and `Master` needs reads and writes that
+        // return rather than wait.
+        // SAFETY: master is a valid open descriptor.
+        let flags = unsafe { libc::fcntl(master.as_raw_fd(), libc::F_GETFL) };
+        // SAFETY: as above.
+        if flags == -1 || unsafe { libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        command
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave))
+            .env("TERM", "xterm-256color");
+        // A new session with the terminal as its controlling tty, so job
+        // control, `SIGWINCH` and Ctrl-C work.
+        // SAFETY: only async-signal-safe calls between fork and exec.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let spawned = usage::spawn(command);
+
+        // The command still holds the slave side; drop it so reading the
+        // master ends once the program is gone.
+        command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null());
+        Ok((spawned?.0, Pty { master }))
+    }
+
+    fn winsize(size: TerminalSize) -> libc::winsize {
+        libc::winsize {
+            ws_row: size.rows,
+            ws_col: size.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::TerminalSize;
+    use crate::usage::Reaper;
+    use std::io;
+    use tokio::process::Command;
+
+    pub struct Pty;
+
+    impl Pty {
+        pub fn resize(&self, _size: TerminalSize) -> io::Result<()> {
+            Err(unsupported())
+        }
+
+        pub fn reader(&self) -> io::Result<tokio::fs::File> {
+            Err(unsupported())
+        }
+
+        pub fn writer(&self) -> io::Result<tokio::fs::File> {
+            Err(unsupported())
+        }
+    }
+
+    pub fn spawn(_command: &mut Command, _size: TerminalSize) -> io::Result<(Reaper, Pty)> {
+        Err(unsupported())
+    }
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "terminal mode needs a Unix host")
+    }
+}
@@ -0,0 +1,360 @@
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, Pool, Sqlite};
+use utoipa::ToSchema;
+
+use crate::{
+    auth::Claims,
+    db::AppState,
+    executor::{RunMode, RunResult},
+    usage::Usage,
+};
+
+/// What a user of one role may use a day; `None` for no limit.
+#[derive(Clone, Copy)]
+pub struct Quota {
+    pub cpu_ms: Option<u64>,
+    pub runs: Option<u64>,
+    pub artifact_bytes: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct Quotas {
+    pub user: Quota,
+    pub admin: Quota,
+}
+
+impl Quotas {
+    /// The quota of users whose `role` in the users table is `role`. Roles
+    /// other than `admin` get that of `user`.
+    fn of(&self, role: &str) -> Quota {
+        match role {
+            "admin" => self.admin,
+            _ => self.user,
+        }
+    }
+}
+
+/// What a user used on one day, UTC.
+#[derive(FromRow, Default)]
+struct Consumption {
+    cpu_ms: i64,
+    runs: i64,
+    artifact_bytes: i64,
+}
+
+/// Why a run wasn't started.
+#[derive(Debug)]
+pub struct Refusal {
+    pub status: StatusCode,
+    pub message: String,
+    /// Seconds until the quota that was used up resets.
+    pub retry_after: Option<u64>,
+}
+
+impl From<sqlx::Error> for Refusal {
+    fn from(e: sqlx::Error) -> Self {
+        Refusal { status: StatusCode::INTERNAL_SERVER_ERROR, message: e.to_string(), retry_after: None }
+    }
+}
+
+impl IntoResponse for Refusal {
+    fn into_response(self) -> Response {
+        match self.retry_after {
+            Some(secs) => (self.status, [(header::RETRY_AFTER, secs.to_string())], self.message).into_response(),
+            None => (self.status, self.message).into_response(),
+        }
+    }
+}
+
+/// Today, UTC, as days are kept in `daily_usage`.
+fn today() -> String {
+    Utc::now().date_naive().to_string()
+}
+
+/// When today's consumption is forgotten.
+fn resets_at() -> DateTime<Utc> {
+    let tomorrow = Utc::now().date_naive() + Days::new(1);
+    tomorrow.and_hms_opt(0, 0, 0).expect("midnight exists").and_utc()
+}
+
+async fn role(db: &Pool<Sqlite>, user_id: &str) -> Result<String, sqlx::Error> {
+    let role = sqlx::query_scalar::<_, String>("SELECT role FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(db)
+        .await?;
+    Ok(role.unwrap_or_else(|| "user".to_string()))
+}
+
+async fn used_on(db: &Pool<Sqlite>, user_id: &str, day: &str) -> Result<Consumption, sqlx::Error> {
+    let used = sqlx::query_as::<_, Consumption>(
+        "SELECT cpu_ms, runs, artifact_bytes FROM daily_usage WHERE user_id = ? AND day = ?",
+    )
+    .bind(user_id)
+    .bind(day)
+    .fetch_optional(db)
+    .await?;
+    Ok(used.unwrap_or_default())
+}
+
+/// Whom a run's consumption is charged to, and for which day.
+pub struct Account {
+    db: Pool<Sqlite>,
+    user_id: String,
+    /// The day the run was admitted on, which it's charged to even if it
+    /// ends after midnight.
+    day: String,
+}
+
+impl Account {
+    /// Adds what a run used to the user's consumption of the day it was
+    /// admitted on, from the results of the programs it ran.
+    pub async fn charge(&self, mode: RunMode, results: &[RunResult]) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO daily_usage (user_id, day, cpu_ms, artifact_bytes) VALUES (?, ?, ?, ?)
+             ON CONFLICT (user_id, day) DO UPDATE SET
+                 cpu_ms = cpu_ms + excluded.cpu_ms,
+                 artifact_bytes = artifact_bytes + excluded.artifact_bytes",
+        )
+        .bind(&self.user_id)
+        .bind(&self.day)
+        .bind(results.iter().map(|result| cpu_ms(mode, result)).sum::<u64>() as i64)
+        .bind(results.iter().map(artifact_bytes).sum::<u64>() as i64)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Lets the user start a run if none of their quotas is used up, and counts
+/// it, returning the account to charge the run to. Runs already going are
+/// not stopped when a quota runs out, so a user can go over by what those
+/// use.
+pub async fn admit(state: &AppState, claims: &Claims) -> Result<Account, Refusal> {
+    let quota = state.config.quotas.of(&role(&state.db, &claims.sub).await?);
+    count(&state.db, &claims.sub, quota).await
+}
+
+/// `admit` for a user whose quota is `quota`.
+async fn count(db: &Pool<Sqlite>, user_id: &str, quota: Quota) -> Result<Account, Refusal> {
+    let day = today();
+    sqlx::query("INSERT INTO daily_usage (user_id, day) VALUES (?, ?) ON CONFLICT (user_id, day) DO NOTHING")
+        .bind(user_id)
+        .bind(&day)
+        .execute(db)
+        .await?;
+    // Checked and counted in one statement, so runs started at the same time
+    // can't all get the last one.
+    let limit = |limit: Option<u64>| limit.map(|limit| limit as i64);
+    let counted = sqlx::query(
+        "UPDATE daily_usage SET runs = runs + 1
+         WHERE user_id = ?1 AND day = ?2
+             AND (?3 IS NULL OR runs < ?3) AND (?4 IS NULL OR cpu_ms < ?4) AND (?5 IS NULL OR artifact_bytes < ?5)
+         RETURNING runs",
+    )
+    .bind(user_id)
+    .bind(&day)
+    .bind(limit(quota.runs))
+    .bind(limit(quota.cpu_ms))
+    .bind(limit(quota.artifact_bytes))
+    .fetch_optional(db)
+    .await?;
+    if counted.is_some() {
+        return Ok(Account { db: db.clone(), user_id: user_id.to_string(), day });
+    }
+
+    let used = used_on(db, user_id, &day).await?;
+    let over = |used: i64, limit: Option<u64>| limit.is_some_and(|limit| used as u64 >= limit);
+    let exhausted = if over(used.runs, quota.runs) {
+        "run"
+    } else if over(used.cpu_ms, quota.cpu_ms) {
+        "CPU time"
+    } else {
+        "artifact storage"
+    };
+    let resets_at = resets_at();
+    Err(Refusal {
+        status: StatusCode::TOO_MANY_REQUESTS,
+        message: format!("Daily {} quota used up, it resets at {}", exhausted, resets_at.format("%H:%M UTC")),
+        retry_after: Some((resets_at - Utc::now()).num_seconds().max(1) as u64),
+    })
+}
+
+/// CPU time of a run: rustc's, and the program's. A wasm guest runs on the
+/// server's own threads, so its wall time counts instead.
+fn cpu_ms(mode: RunMode, result: &RunResult) -> u64 {
+    let time = |usage: &Usage| usage.user_time_ms + usage.system_time_ms;
+    let compile = result.usage.compile.as_ref().map_or(0, time);
+    let program = match (&result.usage.cgroup, &result.usage.program) {
+        (Some(cgroup), _) => cgroup.cpu_usage_ms,
+        (None, Some(program)) => time(program),
+        (None, None) if mode == RunMode::Wasm => result.duration_ms,
+        (None, None) => 0,
+    };
+    compile + program
+}
+
+/// Bytes a run left in the artifacts directory.
+fn artifact_bytes(result: &RunResult) -> u64 {
+    let files = result.output_files.as_ref().map_or(0, |files| files.files.iter().map(|file| file.size).sum());
+    result.artifact_size.unwrap_or(0) + files
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Meter {
+    pub used: u64,
+    /// `null` when there is no limit.
+    pub limit: Option<u64>,
+}
+
+impl Meter {
+    fn new(used: i64, limit: Option<u64>) -> Self {
+        Meter { used: used as u64, limit }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageResponse {
+    /// The role the quotas are those of, `user` or `admin`.
+    pub role: String,
+    /// The day counted so far, UTC.
+    pub day: NaiveDate,
+    /// When the counts go back to zero.
+    pub resets_at: DateTime<Utc>,
+    /// CPU time of rustc and the programs, in milliseconds.
+    pub cpu_ms: Meter,
+    /// Runs started.
+    pub runs: Meter,
+    /// Bytes of build outputs and files programs wrote.
+    pub artifact_bytes: Meter,
+}
+
+// GET /me/usage
+#[utoipa::path(
+    get,
+    path = "/me/usage",
+    responses(
+        (status = 200, description = "Today's consumption and the user's limits", body = UsageResponse),
+        (status = 401, description = "Unauthorized")
+    ),
+    tag = "usage",
+    security(
+        ("jwt" = [])
+    )
+)]
+pub async fn usage_handler(
+    State(state): State<AppState>,
+    claims: Claims,
+) -> Result<Json<UsageResponse>, (StatusCode, String)> {
+    let internal = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let role = role(&state.db, &claims.sub).await.map_err(internal)?;
+    let quota = state.config.quotas.of(&role);
+    let resets_at = resets_at();
+    let day = resets_at.date_naive() - Days::new(1);
+    let used = used_on(&state.db, &claims.sub, &day.to_string()).await.map_err(internal)?;
+
+    Ok(Json(UsageResponse {
+        day,
+        resets_at,
+        cpu_ms: Meter::new(used.cpu_ms, quota.cpu_ms),
+        runs: Meter::new(used.runs, quota.runs),
+        artifact_bytes: Meter::new(used.artifact_bytes, quota.artifact_bytes),
+        role,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    const NONE: Quota = Quota { cpu_ms: None, runs: None, artifact_bytes: None };
+
+    async fn db() -> Pool<Sqlite> {
+        // One connection, since each in-memory connection is a database of its own
+        let db = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+        crate::db::create_tables(&db).await;
+        for user in ["u1", "u2"] {
+            sqlx::query("INSERT INTO users (id, username, password_hash) VALUES (?, ?, '')")
+                .bind(user)
+                .bind(user)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+        db
+    }
+
+    fn used(cpu_ms: u64, artifact_bytes: u64) -> RunResult {
+        let usage = Usage { user_time_ms: cpu_ms, ..Default::default() };
+        let mut result = RunResult { artifact_size: Some(artifact_bytes), ..Default::default() };
+        result.usage.program = Some(usage);
+        result
+    }
+
+    async fn refusal(db: &Pool<Sqlite>, quota: Quota) -> String {
+        match count(db, "u1", quota).await {
+            Ok(_) => panic!("the run was admitted"),
+            Err(refusal) => {
+                assert_eq!(refusal.status, StatusCode::TOO_MANY_REQUESTS);
+                assert!(refusal.retry_after.is_some_and(|secs| secs <= 24 * 3600));
+                refusal.message
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn admits_runs_until_the_run_quota_is_used_up() {
+        let db = db().await;
+        let quota = Quota { runs: Some(2), ..NONE };
+        assert!(count(&db, "u1", quota).await.is_ok());
+        assert!(count(&db, "u1", quota).await.is_ok());
+        assert!(refusal(&db, quota).await.starts_with("Daily run quota used up"));
+        // Refused runs aren't counted, and other users have quotas of their own
+        assert_eq!(used_on(&db, "u1", &today()).await.unwrap().runs, 2);
+        assert!(count(&db, "u2", quota).await.is_ok());
+        // Without limits there's no end to it
+        for _ in 0..5 {
+            assert!(count(&db, "u1", NONE).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_runs_once_cpu_time_or_storage_is_used_up() {
+        let db = db().await;
+        let quota = Quota { cpu_ms: Some(1000), artifact_bytes: Some(4096), ..NONE };
+        let account = count(&db, "u1", quota).await.unwrap();
+        account.charge(RunMode::Native, &[used(600, 0), used(300, 1024)]).await.unwrap();
+        let account = count(&db, "u1", quota).await.unwrap();
+        account.charge(RunMode::Native, &[used(100, 1024)]).await.unwrap();
+        assert!(refusal(&db, quota).await.starts_with("Daily CPU time quota used up"));
+
+        let quota = Quota { artifact_bytes: Some(2048), ..NONE };
+        assert!(refusal(&db, quota).await.starts_with("Daily artifact storage quota used up"));
+        let used = used_on(&db, "u1", &today()).await.unwrap();
+        assert_eq!((used.runs, used.cpu_ms, used.artifact_bytes), (2, 1000, 2048));
+    }
+
+    #[tokio::test]
+    async fn charges_the_day_a_run_was_admitted_on() {
+        let db = db().await;
+        let account = count(&db, "u1", NONE).await.unwrap();
+        assert_eq!(account.day, today());
+        // As if midnight passed while it ran: the day it was admitted on is
+        // over, and today's row is a new one
+        sqlx::query("UPDATE daily_usage SET day = '2000-01-01'").execute(&db).await.unwrap();
+        let admitted_before = Account { day: "2000-01-01".to_string(), ..account };
+        admitted_before.charge(RunMode::Native, &[used(250, 100)]).await.unwrap();
+
+        let then = used_on(&db, "u1", "2000-01-01").await.unwrap();
+        assert_eq!((then.runs, then.cpu_ms, then.artifact_bytes), (1, 250, 100));
+        let now = used_on(&db, "u1", &today()).await.unwrap();
+        assert_eq!((now.runs, now.cpu_ms, now.artifact_bytes), (0, 0, 0));
+    }
+}
@@ -0,0 +1,304 @@
+use axum::{
+    extract::{ConnectInfo, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use utoipa::ToSchema;
+
+use crate::{auth, db::AppState};
+
+/// Buckets that refilled completely are dropped this often.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A token bucket: up to `burst` requests at once, refilled at `per_minute`.
+#[derive(Clone, Copy)]
+pub struct Policy {
+    pub per_minute: f64,
+    pub burst: f64,
+}
+
+impl Policy {
+    /// `None` when `per_minute` is 0, for no limit.
+    pub fn new(per_minute: f64, burst: f64) -> Option<Self> {
+        (per_minute > 0.0).then_some(Self { per_minute, burst: burst.max(1.0) })
+    }
+
+    /// Requests a bucket that was at `tokens` when `since` holds by `now`.
+    fn level(&self, tokens: f64, since: Instant, now: Instant) -> f64 {
+        let refilled = now.duration_since(since).as_secs_f64() * self.per_minute / 60.0;
+        (tokens + refilled).min(self.burst)
+    }
+}
+
+#[derive(Clone)]
+pub struct Policies {
+    /// Runs without a token, per IP address.
+    pub anonymous: Option<Policy>,
+    /// Runs of a signed-in user.
+    pub user: Option<Policy>,
+    /// Logins and registrations, per IP address.
+    pub login: Option<Policy>,
+}
+
+/// Who a run counts against.
+pub enum Client {
+    Anonymous(IpAddr),
+    User(String),
+}
+
+impl Client {
+    /// The user whose token came with the request, as a bearer header or a
+    /// `token` query parameter, or else the peer's address. A token that
+    /// isn't valid counts as none; the handler refuses it.
+    fn of(addr: SocketAddr, headers: &HeaderMap, query: &RunQuery) -> Self {
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match bearer.or(query.token.as_deref()).and_then(|token| auth::verify(token).ok()) {
+            Some(claims) => Client::User(claims.sub),
+            None => Client::Anonymous(addr.ip()),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct RunQuery {
+    token: Option<String>,
+    /// Set when reattaching to a `/ws` session.
+    session_id: Option<String>,
+}
+
+/// The buckets of one policy, by client.
+#[derive(Clone)]
+struct Limiter {
+    policy: Option<Policy>,
+    inner: Arc<Mutex<Buckets>>,
+}
+
+struct Buckets {
+    /// Tokens left, and when that was.
+    buckets: HashMap<String, (f64, Instant)>,
+    swept: Instant,
+    allowed: u64,
+    limited: u64,
+}
+
+impl Limiter {
+    fn new(policy: Option<Policy>) -> Self {
+        let buckets = Buckets { buckets: HashMap::new(), swept: Instant::now(), allowed: 0, limited: 0 };
+        Self { policy, inner: Arc::new(Mutex::new(buckets)) }
+    }
+
+    /// Takes a request from `key`'s bucket, or says how long until there is
+    /// one to take.
+    fn take(&self, key: &str) -> Result<(), Duration> {
+        self.take_at(key, Instant::now())
+    }
+
+    fn take_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let Some(policy) = self.policy else { return Ok(()) };
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        if now.duration_since(inner.swept) >= SWEEP_INTERVAL {
+            // A full bucket is the same as none
+            inner.buckets.retain(|_, (tokens, since)| policy.level(*tokens, *since, now) < policy.burst);
+            inner.swept = now;
+        }
+        let (tokens, since) = inner.buckets.entry(key.to_string()).or_insert((policy.burst, now));
+        *tokens = policy.level(*tokens, *since, now);
+        *since = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            inner.allowed += 1;
+            Ok(())
+        } else {
+            inner.limited += 1;
+            Err(Duration::from_secs_f64((1.0 - *tokens) * 60.0 / policy.per_minute))
+        }
+    }
+
+    fn stats(&self) -> Option<LimiterStats> {
+        self.policy?;
+        let inner = self.inner.lock().unwrap();
+        Some(LimiterStats { allowed: inner.allowed, limited: inner.limited, clients: inner.buckets.len() })
+    }
+}
+
+/// Token-bucket limits on runs and logins.
+#[derive(Clone)]
+pub struct RateLimits {
+    anonymous: Limiter,
+    user: Limiter,
+    login: Limiter,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LimiterStats {
+    /// Requests let through since the server started.
+    pub allowed: u64,
+    /// Requests refused with 429.
+    pub limited: u64,
+    /// Clients with a bucket that isn't full.
+    pub clients: usize,
+}
+
+/// Counters of each policy; a policy that is off has none.
+#[derive(Serialize, ToSchema)]
+pub struct RateLimitStats {
+    pub anonymous: Option<LimiterStats>,
+    pub user: Option<LimiterStats>,
+    pub login: Option<LimiterStats>,
+}
+
+impl RateLimits {
+    pub fn new(policies: &Policies) -> Self {
+        Self {
+            anonymous: Limiter::new(policies.anonymous),
+            user: Limiter::new(policies.user),
+            login: Limiter::new(policies.login),
+        }
+    }
+
+    /// Counts a run against `client`.
+    pub fn run(&self, client: &Client) -> Result<(), Duration> {
+        match client {
+            Client::Anonymous(ip) => self.anonymous.take(&ip.to_string()),
+            Client::User(id) => self.user.take(id),
+        }
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        RateLimitStats { anonymous: self.anonymous.stats(), user: self.user.stats(), login: self.login.stats() }
+    }
+}
+
+/// Whole seconds to wait, as `Retry-After` has them.
+pub fn retry_after(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+fn too_many_requests(wait: Duration) -> Response {
+    let secs = retry_after(wait);
+    let message = format!("Too many requests, retry in {} s", secs);
+    (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, secs.to_string())], message).into_response()
+}
+
+/// Layer for routes that start a run. Reattaching to a `/ws` session doesn't
+/// count, it's the same run.
+pub async fn limit_runs(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let query = Query::<RunQuery>::try_from_uri(request.uri()).map(|Query(query)| query).unwrap_or_default();
+    if query.session_id.is_some() {
+        return next.run(request).await;
+    }
+    let client = Client::of(addr, request.headers(), &query);
+    match state.rate_limits.run(&client) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => too_many_requests(wait),
+    }
+}
+
+/// Layer for the login and register routes.
+pub async fn limit_logins(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match state.rate_limits.login.take(&addr.ip().to_string()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => too_many_requests(wait),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_minute: f64, burst: f64) -> Limiter {
+        Limiter::new(Policy::new(per_minute, burst))
+    }
+
+    #[test]
+    fn lets_a_burst_through_then_says_how_long_to_wait() {
+        let limiter = limiter(60.0, 3.0);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.take_at("a", now), Ok(()));
+        }
+        // One a second at 60 a minute
+        assert_eq!(limiter.take_at("a", now), Err(Duration::from_secs(1)));
+        let stats = limiter.stats().unwrap();
+        assert_eq!((stats.allowed, stats.limited, stats.clients), (3, 1, 1));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = limiter(60.0, 2.0);
+        let now = Instant::now();
+        assert!(limiter.take_at("a", now).is_ok());
+        assert!(limiter.take_at("a", now).is_ok());
+        // Half a token back after half a second
+        let wait = limiter.take_at("a", now + Duration::from_millis(500)).unwrap_err();
+        assert!((wait.as_secs_f64() - 0.5).abs() < 1e-6, "{:?}", wait);
+        assert!(limiter.take_at("a", now + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn refills_no_more_than_the_burst() {
+        let limiter = limiter(60.0, 2.0);
+        let now = Instant::now();
+        assert!(limiter.take_at("a", now).is_ok());
+        let later = now + Duration::from_secs(3600);
+        assert!(limiter.take_at("a", later).is_ok());
+        assert!(limiter.take_at("a", later).is_ok());
+        assert!(limiter.take_at("a", later).is_err());
+    }
+
+    #[test]
+    fn keeps_clients_apart() {
+        let limiter = limiter(1.0, 1.0);
+        let now = Instant::now();
+        assert!(limiter.take_at("a", now).is_ok());
+        assert!(limiter.take_at("a", now).is_err());
+        assert!(limiter.take_at("b", now).is_ok());
+    }
+
+    #[test]
+    fn forgets_buckets_that_refilled() {
+        let limiter = limiter(1.0, 1.0);
+        let now = Instant::now();
+        assert!(limiter.take_at("a", now).is_ok());
+        assert!(limiter.take_at("b", now + Duration::from_secs(30)).is_ok());
+        // By the next sweep "a" is full again, "b" isn't yet
+        let _ = limiter.take_at("c", now + SWEEP_INTERVAL);
+        let buckets = &limiter.inner.lock().unwrap().buckets;
+        assert!(!buckets.contains_key("a"));
+        assert!(buckets.contains_key("b"));
+    }
+
+    #[test]
+    fn a_policy_of_zero_is_no_limit() {
+        assert!(Policy::new(0.0, 5.0).is_none());
+        let limiter = limiter(0.0, 5.0);
+        for _ in 0..100 {
+            assert!(limiter.take("a").is_ok());
+        }
+        assert!(limiter.stats().is_none());
+        // A burst below one still lets a request through.
+        assert_eq!(Policy::new(10.0, 0.0).map(|policy| policy.burst), Some(1.0));
+    }
+}
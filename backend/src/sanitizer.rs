@@ -0,0 +1,317 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SanitizerKind {
+    Address,
+    Thread,
+    Leak,
+}
+
+impl SanitizerKind {
+    /// Extra `rustc` flags for an instrumented build. Debug info is needed for
+    /// source locations in the report.
+    pub fn rustc_args(self) -> Vec<String> {
+        let name = match self {
+            SanitizerKind::Address => "address",
+            SanitizerKind::Thread => "thread",
+            SanitizerKind::Leak => "leak",
+        };
+        let mut args = vec![
+            format!("-Zsanitizer={}", name),
+            "-Cdebuginfo=2".to_string(),
+            "-Cforce-frame-pointers=yes".to_string(),
+        ];
+        // TSan changes the ABI, and std is only shipped uninstrumented.
+        // Rebuilding std per run is far too slow, so accept the mismatch; the
+        // price is that races inside std go unnoticed. MSan isn't offered:
+        // against an uninstrumented std it flags every read of memory std
+        // initialized, so correct programs would fail.
+        if self == SanitizerKind::Thread {
+            args.push("-Cunsafe-allow-abi-mismatch=sanitizer".to_string());
+        }
+        args
+    }
+
+    /// Runtime options for the instrumented binary.
+    pub fn env(self) -> (&'static str, &'static str) {
+        match self {
+            SanitizerKind::Address => ("ASAN_OPTIONS", "detect_leaks=1:color=never"),
+            SanitizerKind::Thread => ("TSAN_OPTIONS", "color=never"),
+            SanitizerKind::Leak => ("LSAN_OPTIONS", "color=never"),
+        }
+    }
+}
+
+/// One report printed by a sanitizer runtime, e.g. a heap-use-after-free or a
+/// data race.
+#[derive(Serialize, Clone, Debug)]
+pub struct Finding {
+    /// `AddressSanitizer`, `ThreadSanitizer`, ...
+    pub sanitizer: String,
+    /// Short error kind such as `heap-use-after-free` or `data race`.
+    pub kind: String,
+    /// The full headline of the report.
+    pub message: String,
+    /// Each stack in the report with the line that introduces it, e.g.
+    /// "READ of size 4 at ... thread T0" or "freed by thread T0 here:".
+    pub stacks: Vec<Stack>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Stack {
+    pub title: String,
+    pub frames: Vec<StackFrame>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StackFrame {
+    pub index: usize,
+    pub function: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+/// Extracts every sanitizer report from a program's stderr.
+pub fn parse_reports(stderr: &str) -> Vec<Finding> {
+    let mut findings: Vec<Finding> = Vec::new();
+
+    for line in stderr.lines() {
+        let trimmed = line.trim();
+
+        if let Some(finding) = parse_headline(trimmed) {
+            findings.push(finding);
+            continue;
+        }
+        let Some(finding) = findings.last_mut() else {
+            continue;
+        };
+        if finding.summary.is_some() {
+            continue;
+        }
+
+        if let Some(summary) = trimmed.strip_prefix("SUMMARY: ") {
+            finding.summary = Some(summary.to_string());
+        } else if let Some(frame) = parse_frame(trimmed) {
+            if finding.stacks.is_empty() {
+                finding.stacks.push(Stack { title: String::new(), frames: Vec::new() });
+            }
+            finding.stacks.last_mut().unwrap().frames.push(frame);
+        } else if !trimmed.is_empty() && !trimmed.starts_with("==") && !trimmed.starts_with("0x") {
+            finding.stacks.push(Stack { title: trimmed.to_string(), frames: Vec::new() });
+        }
+    }
+
+    for finding in &mut findings {
+        finding.stacks.retain(|s| !s.frames.is_empty());
+    }
+    findings
+}
+
+// "==123==ERROR: AddressSanitizer: heap-use-after-free on address 0x..."
+// "WARNING: ThreadSanitizer: data race (pid=123)"
+fn parse_headline(line: &str) -> Option<Finding> {
+    let line = line.trim_start_matches(|c: char| c == '=' || c.is_ascii_digit());
+    let rest = line
+        .strip_prefix("ERROR: ")
+        .or_else(|| line.strip_prefix("WARNING: "))?;
+    let (sanitizer, description) = rest.split_once(": ")?;
+    if !sanitizer.ends_with("Sanitizer") {
+        return None;
+    }
+
+    let kind = description
+        .split(" on ")
+        .next()
+        .unwrap_or(description)
+        .split(" (")
+        .next()
+        .unwrap_or(description)
+        .trim()
+        .to_string();
+
+    Some(Finding {
+        sanitizer: sanitizer.to_string(),
+        kind,
+        message: rest.to_string(),
+        stacks: Vec::new(),
+        summary: None,
+    })
+}
+
+// ASan/MSan/LSan: "#0 0x55d1c2 in foo::bar /src/main.rs:5:10"
+// TSan:           "#0 foo::bar /src/main.rs:5:10 (main+0x1234)"
+fn parse_frame(line: &str) -> Option<StackFrame> {
+    let rest = line.strip_prefix('#')?;
+    let (index, rest) = rest.split_once(' ')?;
+    let index = index.parse().ok()?;
+
+    let mut rest = rest.trim();
+    if rest.starts_with("0x") {
+        rest = rest.split_once(' ').map(|(_, r)| r).unwrap_or("").trim();
+    }
+    rest = rest.strip_prefix("in ").unwrap_or(rest);
+    // Drop trailing "(module+0xoffset)" and "(BuildId: ...)" groups
+    while rest.ends_with(')') {
+        if let Some(pos) = rest.rfind(" (") {
+            rest = &rest[..pos];
+        } else if rest.starts_with('(') {
+            rest = "";
+        } else {
+            break;
+        }
+    }
+
+    let (function, location) = match rest.rsplit_once(' ') {
+        Some((function, last)) => match parse_location(last) {
+            Some(location) => (function, Some(location)),
+            None => (rest, None),
+        },
+        None => (rest, None),
+    };
+    let (file, line, column) = match location {
+        Some((file, line, column)) => (Some(file), Some(line), column),
+        None => (None, None, None),
+    };
+
+    Some(StackFrame {
+        index,
+        function: function.trim().to_string(),
+        file,
+        line,
+        column,
+    })
+}
+
+// "/src/main.rs:5:10" or "/src/main.rs:5"
+fn parse_location(text: &str) -> Option<(String, usize, Option<usize>)> {
+    let mut parts = text.rsplitn(3, ':');
+    let last = parts.next()?.parse::<usize>().ok()?;
+    let middle = parts.next()?;
+    match middle.parse::<usize>() {
+        Ok(line) => Some((parts.next()?.to_string(), line, Some(last))),
+        Err(_) => {
+            let file = match parts.next() {
+                Some(prefix) => format!("{}:{}", prefix, middle),
+                None => middle.to_string(),
+            };
+            Some((file, last, None))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASAN: &str = "\
+=================================================================
+==4242==ERROR: AddressSanitizer: heap-use-after-free on address 0x602000000010 at pc 0x55d1c2 bp 0x7ffc sp 0x7ffc
+READ of size 4 at 0x602000000010 thread T0
+    #0 0x55d1c2 in main::main /tmp/run_1/main.rs:5:20
+    #1 0x55d1d3 in core::ops::function::FnOnce::call_once /rustc/abc/library/core/src/ops/function.rs:250:5
+    #2 0x7f0e8a in __libc_start_main (/lib/x86_64-linux-gnu/libc.so.6+0x29d8f) (BuildId: 89c3)
+
+0x602000000010 is located 0 bytes inside of 4-byte region [0x602000000010,0x602000000014)
+freed by thread T0 here:
+    #0 0x55a001 in free
+    #1 0x55d1b0 in main::main /tmp/run_1/main.rs:4:5
+
+SUMMARY: AddressSanitizer: heap-use-after-free /tmp/run_1/main.rs:5:20 in main::main
+Shadow bytes around the buggy address:
+    #9 0x0 in ignored::after_summary
+";
+
+    #[test]
+    fn parses_an_address_sanitizer_report() {
+        let findings = parse_reports(ASAN);
+        assert_eq!(findings.len(), 1);
+        let finding = &findings[0];
+        assert_eq!(finding.sanitizer, "AddressSanitizer");
+        assert_eq!(finding.kind, "heap-use-after-free");
+        assert!(finding.message.starts_with("AddressSanitizer: heap-use-after-free on address"));
+        assert_eq!(
+            finding.summary.as_deref(),
+            Some("AddressSanitizer: heap-use-after-free /tmp/run_1/main.rs:5:20 in main::main")
+        );
+
+        // The region description has no frames of its own and is dropped.
+        let titles: Vec<&str> = finding.stacks.iter().map(|stack| stack.title.as_str()).collect();
+        assert_eq!(titles, ["READ of size 4 at 0x602000000010 thread T0", "freed by thread T0 here:"]);
+
+        let read = &finding.stacks[0].frames;
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[0].index, 0);
+        assert_eq!(read[0].function, "main::main");
+        assert_eq!(read[0].file.as_deref(), Some("/tmp/run_1/main.rs"));
+        assert_eq!((read[0].line, read[0].column), (Some(5), Some(20)));
+        assert_eq!(read[2].function, "__libc_start_main");
+        assert_eq!(read[2].file, None);
+
+        let freed = &finding.stacks[1].frames;
+        assert_eq!(freed[0].function, "free");
+        assert_eq!(freed[0].line, None);
+    }
+
+    #[test]
+    fn parses_a_thread_sanitizer_report() {
+        let stderr = "\
+==================
+WARNING: ThreadSanitizer: data race (pid=77)
+  Write of size 8 at 0x7b0400000010 by thread T2:
+    #0 main::main::{{closure}} /tmp/run_2/main.rs:7 (main+0x1234)
+  Previous write of size 8 at 0x7b0400000010 by thread T1:
+    #0 main::main::{{closure}} /tmp/run_2/main.rs:7:13 (main+0x1234)
+SUMMARY: ThreadSanitizer: data race /tmp/run_2/main.rs:7 in main::main::{{closure}}
+";
+        let findings = parse_reports(stderr);
+        assert_eq!(findings.len(), 1);
+        let finding = &findings[0];
+        assert_eq!(finding.sanitizer, "ThreadSanitizer");
+        assert_eq!(finding.kind, "data race");
+        assert_eq!(finding.stacks.len(), 2);
+        let frame = &finding.stacks[0].frames[0];
+        assert_eq!(frame.function, "main::main::{{closure}}");
+        assert_eq!((frame.line, frame.column), (Some(7), None));
+        assert_eq!(finding.stacks[1].frames[0].column, Some(13));
+    }
+
+    #[test]
+    fn keeps_several_reports_apart() {
+        let stderr = format!("{}\n==4242==ERROR: LeakSanitizer: detected memory leaks\nDirect leak of 8 byte(s)\n    #0 0x1 in malloc\n", ASAN);
+        let findings = parse_reports(&stderr);
+        let kinds: Vec<&str> = findings.iter().map(|finding| finding.kind.as_str()).collect();
+        assert_eq!(kinds, ["heap-use-after-free", "detected memory leaks"]);
+        assert_eq!(findings[1].stacks[0].title, "Direct leak of 8 byte(s)");
+    }
+
+    #[test]
+    fn ignores_output_that_is_not_a_report() {
+        let stderr = "thread 'main' panicked at main.rs:2:5\n#0 not a frame\nERROR: something else: entirely\n";
+        assert!(parse_reports(stderr).is_empty());
+    }
+
+    #[test]
+    fn parses_locations() {
+        assert_eq!(parse_location("/src/main.rs:5:10"), Some(("/src/main.rs".to_string(), 5, Some(10))));
+        assert_eq!(parse_location("/src/main.rs:5"), Some(("/src/main.rs".to_string(), 5, None)));
+        assert_eq!(parse_location("C:/src/main.rs:5"), Some(("C:/src/main.rs".to_string(), 5, None)));
+        assert_eq!(parse_location("main.rs"), None);
+        assert_eq!(parse_location("(main+0x1234)"), None);
+    }
+
+    #[test]
+    fn only_allows_the_abi_mismatch_for_thread() {
+        let allowed = |kind: SanitizerKind| kind.rustc_args().iter().any(|arg| arg.starts_with("-Cunsafe-allow-abi"));
+        assert!(allowed(SanitizerKind::Thread));
+        assert!(!allowed(SanitizerKind::Address));
+        assert!(!allowed(SanitizerKind::Leak));
+        assert!(serde_json::from_str::<SanitizerKind>("\"memory\"").is_err());
+    }
+}
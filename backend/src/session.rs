@@ -0,0 +1,462 @@
+use futures::stream::{self, Stream};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    executor::{self, Closed, CodeRequest, Input, OutputEncoding, RunEvent, StdinSender},
+    history::Recorder,
+    quota::Account,
+};
+
+/// Runs started over `/ws`, by session id. A run outlives its socket by
+/// `SESSION_GRACE_SECS`, so a client that lost its connection can attach
+/// again and pick up where it left off.
+#[derive(Clone, Default)]
+pub struct Sessions(Arc<Mutex<HashMap<Uuid, Arc<Session>>>>);
+
+impl Sessions {
+    /// Starts running `request`. Its events are kept in the session's log
+    /// until the session is removed, `SESSION_GRACE_SECS` after the run ends.
+    /// A signed-in user's run goes through `recorder` into their history and
+    /// is charged to `account`.
+    pub fn start(
+        &self,
+        config: Arc<Config>,
+        request: CodeRequest,
+        recorder: Option<Recorder>,
+        account: Option<Account>,
+    ) -> Arc<Session> {
+        let (event_tx, event_rx) = mpsc::channel::<RunEvent>(64);
+        let mut event_rx = match recorder {
+            Some(recorder) => recorder.tee(event_rx),
+            None => event_rx,
+        };
+        let (stdin_tx, stdin_rx) = executor::stdin_channel(config.stdin_buffer_limit);
+        let session = Arc::new(Session {
+            id: Uuid::new_v4(),
+            binary: request.output == OutputEncoding::Binary,
+            terminal: request.terminal.is_some(),
+            stdin: stdin_tx,
+            grace: config.session_grace,
+            log: Mutex::new(Log { events: VecDeque::new(), first: 0, bytes: 0, limit: config.session_buffer }),
+            end: watch::Sender::new(0),
+            done: CancellationToken::new(),
+            abandoned: CancellationToken::new(),
+            cancelled: CancellationToken::new(),
+            control: Mutex::new(Control { client: None, attached: 0 }),
+            link: Mutex::new(None),
+        });
+        self.0.lock().unwrap().insert(session.id, session.clone());
+        tokio::spawn(executor::execute(config.clone(), request, event_tx, Some(stdin_rx), account));
+
+        let sessions = self.clone();
+        let pumped = session.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    event = event_rx.recv() => match event {
+                        Some(event) => pumped.push(event),
+                        None => break,
+                    },
+                    _ = pumped.abandoned.cancelled() => {
+                        let message = format!(
+                            "Run stopped, no client attached within {} s",
+                            pumped.grace.as_secs()
+                        );
+                        pumped.push(RunEvent::Error { message });
+                        break;
+                    }
+                    _ = pumped.cancelled.cancelled() => {
+                        pumped.push(RunEvent::Error { message: "Run cancelled".to_string() });
+                        break;
+                    }
+                }
+            }
+            // Kills the program if it's still running.
+            drop(event_rx);
+            pumped.done.cancel();
+            pumped.end.send_modify(|_| {});
+
+            tokio::time::sleep(pumped.grace).await;
+            sessions.0.lock().unwrap().remove(&pumped.id);
+        });
+        session
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Arc<Session>> {
+        self.0.lock().unwrap().get(&id).cloned()
+    }
+
+    /// The session a spectate link that hasn't been revoked belongs to.
+    pub fn spectated(&self, token: Uuid) -> Option<(Arc<Session>, Viewer)> {
+        let sessions = self.0.lock().unwrap();
+        sessions.values().find_map(|session| {
+            let link = session.link.lock().unwrap();
+            let link = link.as_ref().filter(|link| link.token == token)?;
+            let viewer = Viewer { revoked: link.revoked.clone(), _count: link.viewers.clone() };
+            Some((session.clone(), viewer))
+        })
+    }
+}
+
+/// One run and everything it reported so far, up to `SESSION_BUFFER_KB`.
+/// Events are numbered from 0 in the order they happened; a client's offset
+/// is the number of events it has received.
+pub struct Session {
+    pub id: Uuid,
+    /// Output goes out as binary frames where it can.
+    pub binary: bool,
+    pub terminal: bool,
+    stdin: StdinSender,
+    grace: Duration,
+    log: Mutex<Log>,
+    /// Offset the next event gets. Readers wait for it to change.
+    end: watch::Sender<u64>,
+    /// The run has ended and no more events will come.
+    done: CancellationToken,
+    /// Stops the run, because its client didn't come back in time.
+    abandoned: CancellationToken,
+    /// Stops the run at its client's request.
+    cancelled: CancellationToken,
+    control: Mutex<Control>,
+    /// Read-only access for spectators, while the owner shares it.
+    link: Mutex<Option<Link>>,
+}
+
+struct Log {
+    events: VecDeque<(RunEvent, usize)>,
+    /// Offset of the oldest event still kept.
+    first: u64,
+    bytes: usize,
+    limit: usize,
+}
+
+struct Control {
+    /// Cancelled when another client attaches.
+    client: Option<CancellationToken>,
+    /// Clients attached so far, which tells attachments apart.
+    attached: u64,
+}
+
+struct Link {
+    token: Uuid,
+    revoked: CancellationToken,
+    /// Held by every viewer, to count them.
+    viewers: Arc<()>,
+}
+
+/// A spectator following a session through its link.
+pub struct Viewer {
+    /// Cancelled when the owner revokes the link.
+    pub revoked: CancellationToken,
+    _count: Arc<()>,
+}
+
+/// The link spectators of a session use, as the owner sees it.
+pub struct Spectate {
+    pub token: Uuid,
+    /// Spectators following the session right now.
+    pub viewers: usize,
+}
+
+/// Events read from a session's log.
+pub struct Batch {
+    pub events: Vec<RunEvent>,
+    /// Offset of the first of `events`.
+    pub offset: u64,
+    /// Events between the requested offset and `offset` that were already
+    /// dropped from the log.
+    pub missed: u64,
+    /// The run has ended and this was the last of its events.
+    pub done: bool,
+}
+
+impl Session {
+    /// Adds an event to the log, dropping the oldest ones once it holds more
+    /// than `SESSION_BUFFER_KB`. The latest event is always kept.
+    pub fn push(&self, event: RunEvent) {
+        let mut log = self.log.lock().unwrap();
+        let size = size(&event);
+        log.events.push_back((event, size));
+        log.bytes += size;
+        while log.bytes > log.limit && log.events.len() > 1 {
+            let (_, size) = log.events.pop_front().expect("log is not empty");
+            log.bytes -= size;
+            log.first += 1;
+        }
+        let end = log.first + log.events.len() as u64;
+        drop(log);
+        self.end.send_replace(end);
+    }
+
+    /// Every event from `offset` on that is still in the log.
+    pub fn read(&self, offset: u64) -> Batch {
+        let log = self.log.lock().unwrap();
+        let start = offset.clamp(log.first, log.first + log.events.len() as u64);
+        let skip = (start - log.first) as usize;
+        Batch {
+            events: log.events.iter().skip(skip).map(|(event, _)| event.clone()).collect(),
+            offset: start,
+            missed: start.saturating_sub(offset),
+            done: self.done.is_cancelled(),
+        }
+    }
+
+    /// Completes when events past those read so far arrive, or the run ends.
+    pub fn updates(&self) -> watch::Receiver<u64> {
+        self.end.subscribe()
+    }
+
+    /// The session's events from `offset` on, as they happen, until the run
+    /// ends. Each comes with the offset that follows it. A `Session` event
+    /// goes first, and again whenever events had already been dropped.
+    pub fn follow(self: Arc<Self>, offset: u64) -> impl Stream<Item = (u64, RunEvent)> + Send {
+        let follow = Follow { updates: self.updates(), session: self, offset, pending: VecDeque::new(), first: true };
+        stream::unfold(follow, |mut follow| async move {
+            loop {
+                if let Some(next) = follow.pending.pop_front() {
+                    return Some((next, follow));
+                }
+                follow.updates.borrow_and_update();
+                let batch = follow.session.read(follow.offset);
+                if follow.first || batch.missed > 0 {
+                    let event = RunEvent::Session {
+                        session_id: Some(follow.session.id.to_string()),
+                        offset: batch.offset,
+                        missed: batch.missed,
+                    };
+                    follow.pending.push_back((batch.offset, event));
+                    follow.first = false;
+                }
+                for (offset, event) in (batch.offset + 1..).zip(batch.events) {
+                    follow.pending.push_back((offset, event));
+                }
+                follow.offset = follow.pending.back().map_or(batch.offset, |(offset, _)| *offset);
+                if follow.pending.is_empty() && (batch.done || follow.updates.changed().await.is_err()) {
+                    return None;
+                }
+            }
+        })
+    }
+
+    /// Writes `input` to the program's stdin, and once it's taken, adds it
+    /// to the log for spectators.
+    pub async fn send_input(&self, input: Input) -> Result<(), Closed> {
+        let echo = match &input {
+            _ if self.terminal => None,
+            Input::Data(data) => Some(RunEvent::Stdin { data: String::from_utf8_lossy(data).into_owned(), eof: false }),
+            Input::Eof => Some(RunEvent::Stdin { data: String::new(), eof: true }),
+            Input::Resize(_) => None,
+        };
+        self.stdin.send(input).await?;
+        if let Some(echo) = echo {
+            self.push(echo);
+        }
+        Ok(())
+    }
+
+    /// Stops the run, if it's still going.
+    pub fn cancel(&self) {
+        self.cancelled.cancel();
+    }
+
+    /// The session's spectate link, made on first use.
+    pub fn share(&self) -> Spectate {
+        let mut link = self.link.lock().unwrap();
+        let link = link.get_or_insert_with(|| Link {
+            token: Uuid::new_v4(),
+            revoked: CancellationToken::new(),
+            viewers: Arc::new(()),
+        });
+        Spectate { token: link.token, viewers: Arc::strong_count(&link.viewers) - 1 }
+    }
+
+    /// Disconnects the spectators and makes the link stop working. Returns
+    /// whether there was one.
+    pub fn revoke(&self) -> bool {
+        let link = self.link.lock().unwrap().take();
+        link.inspect(|link| link.revoked.cancel()).is_some()
+    }
+
+    /// Makes the caller the session's client, in place of any previous one,
+    /// whose token is cancelled. When the returned attachment is dropped
+    /// and nobody attaches within `SESSION_GRACE_SECS`, the run is stopped.
+    pub fn attach(self: &Arc<Self>) -> Attachment {
+        let token = CancellationToken::new();
+        let mut control = self.control.lock().unwrap();
+        if let Some(previous) = control.client.replace(token.clone()) {
+            previous.cancel();
+        }
+        control.attached += 1;
+        Attachment { session: self.clone(), token, number: control.attached }
+    }
+}
+
+struct Follow {
+    session: Arc<Session>,
+    updates: watch::Receiver<u64>,
+    /// Offset of the next event to read from the log.
+    offset: u64,
+    pending: VecDeque<(u64, RunEvent)>,
+    first: bool,
+}
+
+pub struct Attachment {
+    session: Arc<Session>,
+    /// Cancelled when another client takes over.
+    pub token: CancellationToken,
+    number: u64,
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        let mut control = self.session.control.lock().unwrap();
+        if control.attached != self.number {
+            return;
+        }
+        control.client = None;
+        let session = self.session.clone();
+        let number = self.number;
+        tokio::spawn(async move {
+            tokio::time::sleep(session.grace).await;
+            if session.control.lock().unwrap().attached == number {
+                session.abandoned.cancel();
+            }
+        });
+    }
+}
+
+/// Roughly what an event takes up, to bound the log.
+fn size(event: &RunEvent) -> usize {
+    let data = match event {
+        RunEvent::Stdout { data } | RunEvent::Stderr { data } | RunEvent::CompileError { data } => data.len(),
+        RunEvent::Stdin { data, .. } => data.len(),
+        RunEvent::Output { data, .. } | RunEvent::Terminal { data } => data.len(),
+        _ => 0,
+    };
+    data + 64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    /// Bytes `size` counts for each of the events `push_all` adds.
+    const EVENT: usize = 3 + 64;
+
+    /// A session with no run behind it, whose log holds `limit` bytes.
+    fn session(limit: usize) -> Arc<Session> {
+        let (stdin, _) = executor::stdin_channel(1024);
+        Arc::new(Session {
+            id: Uuid::new_v4(),
+            binary: false,
+            terminal: false,
+            stdin,
+            grace: Duration::ZERO,
+            log: Mutex::new(Log { events: VecDeque::new(), first: 0, bytes: 0, limit }),
+            end: watch::Sender::new(0),
+            done: CancellationToken::new(),
+            abandoned: CancellationToken::new(),
+            cancelled: CancellationToken::new(),
+            control: Mutex::new(Control { client: None, attached: 0 }),
+            link: Mutex::new(None),
+        })
+    }
+
+    /// Pushes `e00`, `e01`, ... as stdout, `count` of them.
+    fn push_all(session: &Session, count: usize) {
+        for i in 0..count {
+            session.push(RunEvent::Stdout { data: format!("e{:02}", i) });
+        }
+    }
+
+    fn data(events: &[RunEvent]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|event| match event {
+                RunEvent::Stdout { data } => data.as_str(),
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_every_event_under_the_limit() {
+        let session = session(10 * EVENT);
+        push_all(&session, 3);
+        let batch = session.read(0);
+        assert_eq!(data(&batch.events), ["e00", "e01", "e02"]);
+        assert_eq!((batch.offset, batch.missed, batch.done), (0, 0, false));
+        assert_eq!(*session.updates().borrow(), 3);
+    }
+
+    #[test]
+    fn drops_the_oldest_events_past_the_limit() {
+        let session = session(3 * EVENT);
+        push_all(&session, 5);
+        assert_eq!(*session.updates().borrow(), 5);
+
+        // From before what's left: starts at the oldest kept, counting the rest as missed
+        let batch = session.read(0);
+        assert_eq!(data(&batch.events), ["e02", "e03", "e04"]);
+        assert_eq!((batch.offset, batch.missed), (2, 2));
+        let batch = session.read(1);
+        assert_eq!((batch.offset, batch.missed, batch.events.len()), (2, 1, 3));
+    }
+
+    #[test]
+    fn reads_from_the_boundaries() {
+        let session = session(3 * EVENT);
+        push_all(&session, 5);
+
+        // The oldest event still kept
+        let batch = session.read(2);
+        assert_eq!(data(&batch.events), ["e02", "e03", "e04"]);
+        assert_eq!((batch.offset, batch.missed), (2, 0));
+        // The last one
+        let batch = session.read(4);
+        assert_eq!(data(&batch.events), ["e04"]);
+        // The end, and past it: nothing yet, nothing missed
+        for offset in [5, 9] {
+            let batch = session.read(offset);
+            assert!(batch.events.is_empty());
+            assert_eq!((batch.offset, batch.missed), (5, 0));
+        }
+    }
+
+    #[test]
+    fn always_keeps_the_latest_event() {
+        let session = session(EVENT / 2);
+        push_all(&session, 2);
+        let batch = session.read(0);
+        assert_eq!(data(&batch.events), ["e01"]);
+        assert_eq!((batch.offset, batch.missed), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn following_replays_from_the_offset_and_ends_with_the_run() {
+        let session = session(3 * EVENT);
+        push_all(&session, 5);
+        session.done.cancel();
+
+        let followed: Vec<(u64, RunEvent)> = session.clone().follow(0).collect().await;
+        assert!(matches!(followed[0], (2, RunEvent::Session { offset: 2, missed: 2, .. })));
+        let offsets: Vec<u64> = followed[1..].iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, [3, 4, 5]);
+        let events: Vec<RunEvent> = followed[1..].iter().map(|(_, event)| event.clone()).collect();
+        assert_eq!(data(&events), ["e02", "e03", "e04"]);
+
+        // Picking up at the end of a finished run only says which session it is
+        let followed: Vec<(u64, RunEvent)> = session.follow(5).collect().await;
+        assert_eq!(followed.len(), 1);
+        assert!(matches!(followed[0], (5, RunEvent::Session { offset: 5, missed: 0, .. })));
+    }
+}
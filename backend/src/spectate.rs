@@ -0,0 +1,192 @@
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::sse::{KeepAlive, Sse},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::{sink::SinkExt, stream::{self, Stream, StreamExt}};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, sync::Arc};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    db::AppState,
+    executor::RunEvent,
+    keepalive::{Beat, Keepalive},
+    session::{Session, Viewer},
+    sse_event, ws_message,
+};
+
+#[derive(Serialize, ToSchema)]
+pub struct SpectateLink {
+    /// Anyone with the token can watch the run, until the link is revoked.
+    pub token: String,
+    /// Where spectators connect, with a WebSocket or as SSE.
+    pub url: String,
+    /// Spectators watching right now.
+    pub viewers: usize,
+}
+
+#[derive(Deserialize)]
+pub struct Resume {
+    /// Number of the run's events the spectator already has.
+    offset: Option<u64>,
+}
+
+fn find_session(state: &AppState, id: &str) -> Result<Arc<Session>, (StatusCode, String)> {
+    Uuid::parse_str(id)
+        .ok()
+        .and_then(|id| state.sessions.get(id))
+        .ok_or((StatusCode::NOT_FOUND, "Session not found".to_string()))
+}
+
+// POST /sessions/:id/spectate
+#[utoipa::path(
+    post,
+    path = "/sessions/{id}/spectate",
+    params(
+        ("id" = String, Path, description = "Session id from the run's `session` event")
+    ),
+    responses(
+        (status = 200, description = "The session's spectate link, made if there was none", body = SpectateLink),
+        (status = 404, description = "Session not found")
+    ),
+    tag = "sessions"
+)]
+pub async fn share_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<SpectateLink>, (StatusCode, String)> {
+    let spectate = find_session(&state, &id)?.share();
+    Ok(Json(SpectateLink {
+        token: spectate.token.to_string(),
+        url: format!("/spectate/{}", spectate.token),
+        viewers: spectate.viewers,
+    }))
+}
+
+// DELETE /sessions/:id/spectate
+#[utoipa::path(
+    delete,
+    path = "/sessions/{id}/spectate",
+    params(
+        ("id" = String, Path, description = "Session id from the run's `session` event")
+    ),
+    responses(
+        (status = 204, description = "Link revoked and spectators disconnected"),
+        (status = 404, description = "Session not found, or not shared")
+    ),
+    tag = "sessions"
+)]
+pub async fn revoke_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if find_session(&state, &id)?.revoke() {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "Session is not shared".to_string()))
+    }
+}
+
+// GET /spectate/:token, as a WebSocket or an SSE stream
+#[utoipa::path(
+    get,
+    path = "/spectate/{token}",
+    params(
+        ("token" = String, Path, description = "Token of a spectate link"),
+        ("offset" = Option<u64>, Query, description = "Events already received, to resume from")
+    ),
+    responses(
+        (status = 200, description = "The run's events as SSE, or as WebSocket messages after an upgrade"),
+        (status = 404, description = "Unknown or revoked spectate link")
+    ),
+    tag = "sessions"
+)]
+pub async fn spectate_handler(
+    ws: Option<WebSocketUpgrade>,
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Query(resume): Query<Resume>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let (session, viewer) = Uuid::parse_str(&token)
+        .ok()
+        .and_then(|token| state.sessions.spectated(token))
+        .ok_or((StatusCode::NOT_FOUND, "Unknown or revoked spectate link".to_string()))?;
+    // EventSource resumes with the id of the last event it got
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let offset = resume.offset.or(last_event_id).unwrap_or(0);
+
+    let events = watch(session.clone(), viewer, offset);
+    Ok(match ws {
+        Some(ws) => {
+            let keepalive = Keepalive::new(&state.config);
+            ws.on_upgrade(move |socket| handle_viewer(socket, session, events, keepalive)).into_response()
+        }
+        None => {
+            let stream = events.map(|(offset, event)| Ok::<_, Infallible>(sse_event(event).id(offset.to_string())));
+            Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+        }
+    })
+}
+
+/// The session's events for a spectator, ending early if the link is revoked.
+fn watch(session: Arc<Session>, viewer: Viewer, offset: u64) -> impl Stream<Item = (u64, RunEvent)> + Send {
+    let events = Box::pin(session.follow(offset));
+    stream::unfold(Some((events, viewer, offset)), |state| async move {
+        let (mut events, viewer, offset) = state?;
+        tokio::select! {
+            next = events.next() => {
+                let (offset, mut event) = next?;
+                if let RunEvent::Session { session_id, .. } = &mut event {
+                    *session_id = None;
+                }
+                Some(((offset, event), Some((events, viewer, offset))))
+            }
+            _ = viewer.revoked.cancelled() => {
+                let event = RunEvent::Error { message: "The spectate link was revoked".to_string() };
+                Some(((offset, event), None))
+            }
+        }
+    })
+}
+
+async fn handle_viewer(
+    socket: WebSocket,
+    session: Arc<Session>,
+    events: impl Stream<Item = (u64, RunEvent)>,
+    keepalive: Keepalive,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut events = std::pin::pin!(events);
+    loop {
+        tokio::select! {
+            next = events.next() => match next {
+                Some((_, event)) => {
+                    keepalive.sent();
+                    if sender.send(ws_message(&session, event)).await.is_err() {
+                        return;
+                    }
+                }
+                None => break,
+            },
+            // Spectators are read-only; whatever they send is ignored
+            message = receiver.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(message)) => keepalive.received(&message),
+            },
+            beat = keepalive.next() => {
+                if sender.send(beat.message()).await.is_err() || matches!(beat, Beat::Close(_)) {
+                    return;
+                }
+            }
+        }
+    }
+    let _ = sender.close().await;
+}
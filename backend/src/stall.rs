@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use crate::cgroup::Group;
+
+/// How often a program is looked at while it might be waiting for input.
+#[cfg(target_os = "linux")]
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Completes once the program has been waiting for input on its stdin for
+/// `limit`, reading nothing in the meantime. The program is `pid`, or with a
+/// cgroup, every process in it. Never completes on hosts other than Linux.
+#[cfg(target_os = "linux")]
+pub async fn waiting_for_input(pid: u32, group: Option<&Group>, limit: Duration) {
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    let mut since = None;
+    let mut last_read = None;
+    loop {
+        ticker.tick().await;
+        let pids = match group {
+            Some(group) => group.pids().await,
+            None => vec![pid],
+        };
+        let (reading, read) = tokio::task::spawn_blocking(move || probe(&pids)).await.unwrap_or_default();
+        // Reads between two looks show as more bytes read.
+        if !reading || last_read != Some(read) {
+            since = None;
+        } else if since.get_or_insert_with(tokio::time::Instant::now).elapsed() >= limit {
+            return;
+        }
+        last_read = Some(read);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn waiting_for_input(_pid: u32, _group: Option<&Group>, _limit: Duration) {
+    std::future::pending().await
+}
+
+/// Whether any thread of `pids` is blocked in a read of stdin, and how many
+/// bytes they read so far, from `/proc`.
+#[cfg(target_os = "linux")]
+fn probe(pids: &[u32]) -> (bool, u64) {
+    let mut reading = false;
+    let mut read = 0;
+    for pid in pids {
+        let proc = std::path::Path::new("/proc").join(pid.to_string());
+        let io = std::fs::read_to_string(proc.join("io")).unwrap_or_default();
+        read += io.lines().find_map(|line| line.strip_prefix("rchar: ")).and_then(|n| n.parse::<u64>().ok()).unwrap_or(0);
+        let Ok(threads) = std::fs::read_dir(proc.join("task")) else {
+            continue;
+        };
+        for thread in threads.flatten() {
+            // The syscall a thread is blocked in and its arguments, e.g.
+            // `0 0x0 0x5581... 0x2000 ...` for `read(0, buf, 8192)`, or
+            // `running`.
+            let syscall = std::fs::read_to_string(thread.path().join("syscall")).unwrap_or_default();
+            let mut fields = syscall.split_whitespace();
+            let number = fields.next().and_then(|n| n.parse::<libc::c_long>().ok());
+            let fd = fields.next();
+            if matches!(number, Some(libc::SYS_read | libc::SYS_readv)) && fd == Some("0x0") {
+                reading = true;
+            }
+        }
+    }
+    (reading, read)
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    ops::Range,
+    path::Path,
+    sync::{LazyLock, Mutex},
+};
+use tokio::process::Command;
+
+use crate::config::Config;
+
+/// User ids runs have now, and where the search for a free one goes on.
+struct Pool {
+    used: HashSet<u32>,
+    next: u32,
+}
+
+static POOL: LazyLock<Mutex<Pool>> = LazyLock::new(|| Mutex::new(Pool { used: HashSet::new(), next: 0 }));
+
+/// A user id of `RUN_UID_BASE`'s range that no other run has for as long as
+/// this lives.
+pub struct Lease(u32);
+
+impl Lease {
+    /// The first free id after the one given out last, so a run's id isn't
+    /// handed on right after it ended.
+    pub fn take(range: &Range<u32>) -> io::Result<Self> {
+        let mut pool = POOL.lock().unwrap();
+        let count = range.end - range.start;
+        for offset in 0..count {
+            let index = (pool.next + offset) % count;
+            if pool.used.insert(range.start + index) {
+                pool.next = (index + 1) % count;
+                return Ok(Self(range.start + index));
+            }
+        }
+        Err(io::Error::other("every run user id is taken"))
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        POOL.lock().unwrap().used.remove(&self.0);
+    }
+}
+
+/// Checks that runs can be given users of their own, which takes root, and
+/// that rustc runs as one, and closes the temp directory to listing so they
+/// find nothing but their own.
+pub fn setup(config: &Config) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        // SAFETY: plain syscall.
+        if unsafe { libc::geteuid() } != 0 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "switching users needs root"));
+        }
+        fs::create_dir_all(&config.temp_dir)?;
+        let temp_dir = std::path::absolute(&config.temp_dir)?;
+        for dir in temp_dir.ancestors().skip(1) {
+            if fs::metadata(dir)?.permissions().mode() & 0o001 == 0 {
+                let message = format!("other users can't get through {}", dir.display());
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+            }
+        }
+        fs::set_permissions(&temp_dir, fs::Permissions::from_mode(0o711))?;
+
+        // The compiler runs as the run's user too, so the toolchain has to
+        // be within its reach.
+        if let Some(range) = &config.run_uids {
+            let mut probe = Command::new("rustc");
+            probe.arg("--version").current_dir(&temp_dir);
+            run_as(&mut probe, range.start);
+            let failure = match probe.as_std_mut().output() {
+                Ok(output) if output.status.success() => None,
+                Ok(output) => Some(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(failure) = failure {
+                let message = format!("rustc doesn't run as user {}: {}", range.start, failure);
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = config;
+        Err(io::Error::new(io::ErrorKind::Unsupported, "run users need a Unix host"))
+    }
+}
+
+/// Makes `dir` and everything in it `uid`'s, and only theirs. Symlinks are
+/// changed, not followed.
+pub fn hand_over(dir: &Path, uid: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{lchown, PermissionsExt};
+
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(path) = pending.pop() {
+            lchown(&path, Some(uid), Some(uid))?;
+            if fs::symlink_metadata(&path)?.is_dir() {
+                for entry in fs::read_dir(&path)? {
+                    pending.push(entry?.path());
+                }
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (dir, uid);
+        Ok(())
+    }
+}
+
+/// Makes `command` switch to `uid` (and the group of the same id, with no
+/// others) right before it starts. Hooks that need root, like joining a
+/// cgroup or a namespace, have to be added first.
+pub fn run_as(command: &mut Command, uid: u32) {
+    #[cfg(unix)]
+    // SAFETY: only setgroups/setgid/setuid between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setgroups(0, std::ptr::null()) != 0 || libc::setgid(uid) != 0 || libc::setuid(uid) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    #[cfg(not(unix))]
+    let _ = (command, uid);
+}
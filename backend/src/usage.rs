@@ -0,0 +1,255 @@
+use serde::Serialize;
+use std::{
+    io,
+    process::{ExitStatus, Output, Stdio},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::process::Command;
+
+pub use imp::Reaper;
+
+/// Resources used by a process and the children it waited for, e.g. rustc
+/// and the linker it runs.
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct Usage {
+    /// Peak resident set size of the largest process, in KiB.
+    pub peak_rss_kb: u64,
+    pub user_time_ms: u64,
+    pub system_time_ms: u64,
+    pub minor_page_faults: u64,
+    /// Faults that had to read from disk.
+    pub major_page_faults: u64,
+    /// Times the process gave up the CPU, usually to wait for I/O.
+    pub voluntary_context_switches: u64,
+    /// Times the process was preempted.
+    pub involuntary_context_switches: u64,
+}
+
+/// How a process ended. `usage` is only known on Unix hosts.
+pub struct Exit {
+    pub status: ExitStatus,
+    pub usage: Option<Usage>,
+}
+
+/// The ends of a spawned process's stdio that were `Stdio::piped()`.
+pub struct Pipes {
+    pub stdin: Option<Box<dyn AsyncWrite + Send + Unpin>>,
+    pub stdout: Option<Box<dyn AsyncRead + Send + Unpin>>,
+    pub stderr: Option<Box<dyn AsyncRead + Send + Unpin>>,
+}
+
+/// Starts `command` and returns the reaper that owns its process, along with
+/// its pipes.
+pub fn spawn(command: &mut Command) -> io::Result<(Reaper, Pipes)> {
+    imp::spawn(command)
+}
+
+/// Makes `command` spawn its process with `fork` rather than `posix_spawn`.
+/// The latter runs the child in the server's memory until `exec`, and the
+/// kernel would report the server's peak RSS as the child's. A forked child
+/// still starts out with a copy of the server's page tables, so small
+/// programs are overstated by a couple of MiB.
+pub fn own_memory(command: &mut Command) {
+    imp::own_memory(command)
+}
+
+/// Like `Command::output`, also returning what the process used.
+pub async fn output(command: &mut Command) -> io::Result<(Output, Option<Usage>)> {
+    own_memory(command);
+    command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let (mut reaper, pipes) = spawn(command)?;
+    let mut stdout_pipe = pipes.stdout.expect("child did not have a handle to stdout");
+    let mut stderr_pipe = pipes.stderr.expect("child did not have a handle to stderr");
+
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let (read_stdout, read_stderr, exit) = tokio::join!(
+        stdout_pipe.read_to_end(&mut stdout),
+        stderr_pipe.read_to_end(&mut stderr),
+        reaper.wait(),
+    );
+    read_stdout?;
+    read_stderr?;
+    let exit = exit?;
+    Ok((Output { status: exit.status, stdout, stderr }, exit.usage))
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::{Exit, Pipes, Usage};
+    use std::{
+        io, mem,
+        os::{fd::OwnedFd, unix::process::ExitStatusExt},
+        process::{Child, ExitStatus},
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::unix::pipe,
+        process::Command,
+        task::JoinHandle,
+    };
+
+    pub fn own_memory(command: &mut Command) {
+        // std only forks when there is a hook to run in the child.
+        // SAFETY: the hook does nothing.
+        unsafe { command.pre_exec(|| Ok(())) };
+    }
+
+    /// Spawned through std rather than tokio, which would reap the process
+    /// itself.
+    pub fn spawn(command: &mut Command) -> io::Result<(Reaper, Pipes)> {
+        let mut child = command.as_std_mut().spawn()?;
+        let pipes = Pipes {
+            stdin: child.stdin.take().map(writer).transpose()?,
+            stdout: child.stdout.take().map(reader).transpose()?,
+            stderr: child.stderr.take().map(reader).transpose()?,
+        };
+        Ok((Reaper::new(child), pipes))
+    }
+
+    fn reader(fd: impl Into<OwnedFd>) -> io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+        Ok(Box::new(pipe::Receiver::from_owned_fd(fd.into())?))
+    }
+
+    fn writer(fd: impl Into<OwnedFd>) -> io::Result<Box<dyn AsyncWrite + Send + Unpin>> {
+        Ok(Box::new(pipe::Sender::from_owned_fd(fd.into())?))
+    }
+
+    /// Waits for a child with `wait4`, which reports its resource usage as
+    /// it reaps it. The child is killed if this is dropped before it exited.
+    pub struct Reaper {
+        pid: libc::pid_t,
+        waiter: JoinHandle<io::Result<Exit>>,
+        // Dropping a std `Child` neither kills nor waits for the process, so
+        // `waiter` is the only one to reap it.
+        _child: Child,
+    }
+
+    impl Reaper {
+        fn new(child: Child) -> Self {
+            let pid = child.id() as libc::pid_t;
+            let waiter = tokio::task::spawn_blocking(move || wait4(pid));
+            Self { pid, waiter, _child: child }
+        }
+
+        /// Cancel safe. Must not be called again once it has returned.
+        pub async fn wait(&mut self) -> io::Result<Exit> {
+            (&mut self.waiter).await.map_err(io::Error::other)?
+        }
+
+        /// The process id, while it hasn't been reaped.
+        pub fn pid(&self) -> Option<u32> {
+            (!self.waiter.is_finished()).then_some(self.pid as u32)
+        }
+
+        pub fn kill(&self) {
+            // Once reaped, the pid may belong to someone else.
+            if !self.waiter.is_finished() {
+                // SAFETY: plain syscall on a pid we haven't reaped.
+                unsafe { libc::kill(self.pid, libc::SIGKILL) };
+            }
+        }
+    }
+
+    impl Drop for Reaper {
+        fn drop(&mut self) {
+            self.kill();
+        }
+    }
+
+    fn wait4(pid: libc::pid_t) -> io::Result<Exit> {
+        let mut status = 0;
+        // SAFETY: rusage is plain data, all zeroes is a valid value.
+        let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+        loop {
+            // SAFETY: both out-pointers are valid for the duration of the call.
+            if unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) } != -1 {
+                break;
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+
+        let millis = |time: libc::timeval| time.tv_sec as u64 * 1000 + time.tv_usec as u64 / 1000;
+        // Linux reports KiB, macOS bytes.
+        let rss_unit = if cfg!(target_vendor = "apple") { 1024 } else { 1 };
+        let usage = Usage {
+            peak_rss_kb: rusage.ru_maxrss as u64 / rss_unit,
+            user_time_ms: millis(rusage.ru_utime),
+            system_time_ms: millis(rusage.ru_stime),
+            minor_page_faults: rusage.ru_minflt as u64,
+            major_page_faults: rusage.ru_majflt as u64,
+            voluntary_context_switches: rusage.ru_nvcsw as u64,
+            involuntary_context_switches: rusage.ru_nivcsw as u64,
+        };
+        Ok(Exit { status: ExitStatus::from_raw(status), usage: Some(usage) })
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::{Exit, Pipes};
+    use std::io;
+    use tokio::{
+        process::{Child, Command},
+        task::JoinHandle,
+    };
+    use tokio_util::sync::CancellationToken;
+
+    pub fn own_memory(_command: &mut Command) {}
+
+    pub fn spawn(command: &mut Command) -> io::Result<(Reaper, Pipes)> {
+        let mut child = command.spawn()?;
+        let pipes = Pipes {
+            stdin: child.stdin.take().map(|stdin| Box::new(stdin) as _),
+            stdout: child.stdout.take().map(|stdout| Box::new(stdout) as _),
+            stderr: child.stderr.take().map(|stderr| Box::new(stderr) as _),
+        };
+        Ok((Reaper::new(child), pipes))
+    }
+
+    /// Waits for a child. The child is killed if this is dropped before it
+    /// exited.
+    pub struct Reaper {
+        kill: CancellationToken,
+        waiter: JoinHandle<io::Result<Exit>>,
+    }
+
+    impl Reaper {
+        fn new(mut child: Child) -> Self {
+            let kill = CancellationToken::new();
+            let killed = kill.clone();
+            let waiter = tokio::spawn(async move {
+                let status = tokio::select! {
+                    status = child.wait() => status?,
+                    _ = killed.cancelled() => {
+                        child.kill().await?;
+                        child.wait().await?
+                    }
+                };
+                Ok(Exit { status, usage: None })
+            });
+            Self { kill, waiter }
+        }
+
+        /// Cancel safe. Must not be called again once it has returned.
+        pub async fn wait(&mut self) -> io::Result<Exit> {
+            (&mut self.waiter).await.map_err(io::Error::other)?
+        }
+
+        pub fn pid(&self) -> Option<u32> {
+            None
+        }
+
+        pub fn kill(&self) {
+            self.kill.cancel();
+        }
+    }
+
+    impl Drop for Reaper {
+        fn drop(&mut self) {
+            self.kill();
+        }
+    }
+}
@@ -0,0 +1,105 @@
+/// Turns a byte stream into text without splitting characters: a multi-byte
+/// sequence cut off at the end of one chunk is held back until the next one
+/// completes it. Bytes that can never be valid UTF-8 become U+FFFD, as with
+/// `String::from_utf8_lossy`.
+#[derive(Default)]
+pub struct Decoder {
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    /// Decodes the next chunk. Returns an empty string if all it held was
+    /// the start of a character.
+    pub fn decode(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut text = String::with_capacity(self.pending.len());
+        let mut tail = Vec::new();
+        let mut chunks = self.pending.utf8_chunks().peekable();
+        while let Some(chunk) = chunks.next() {
+            text.push_str(chunk.valid());
+            let invalid = chunk.invalid();
+            if invalid.is_empty() {
+                continue;
+            }
+            let truncated = std::str::from_utf8(invalid).is_err_and(|e| e.error_len().is_none());
+            if chunks.peek().is_none() && truncated {
+                tail = invalid.to_vec();
+            } else {
+                text.push(char::REPLACEMENT_CHARACTER);
+            }
+        }
+        self.pending = tail;
+        text
+    }
+
+    /// Whatever is still held back once the stream has ended, which can only
+    /// be a truncated character.
+    pub fn finish(self) -> String {
+        String::from_utf8_lossy(&self.pending).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Decoder;
+
+    #[test]
+    fn passes_whole_characters_through() {
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.decode("héllo ✓".as_bytes()), "héllo ✓");
+        assert_eq!(decoder.finish(), "");
+    }
+
+    #[test]
+    fn holds_back_a_character_split_at_every_byte() {
+        let crab = "🦀".as_bytes();
+        for split in 1..crab.len() {
+            let mut decoder = Decoder::default();
+            let mut first = b"a".to_vec();
+            first.extend_from_slice(&crab[..split]);
+            assert_eq!(decoder.decode(&first), "a", "split after {} bytes", split);
+            assert_eq!(decoder.decode(&crab[split..]), "🦀");
+            assert_eq!(decoder.finish(), "");
+        }
+    }
+
+    #[test]
+    fn joins_a_character_spread_over_several_reads() {
+        let mut decoder = Decoder::default();
+        let crab = "🦀".as_bytes();
+        assert_eq!(decoder.decode(&crab[..1]), "");
+        assert_eq!(decoder.decode(&crab[1..2]), "");
+        assert_eq!(decoder.decode(&crab[2..3]), "");
+        assert_eq!(decoder.decode(&[crab[3], b'!']), "🦀!");
+    }
+
+    #[test]
+    fn replaces_invalid_bytes_and_goes_on() {
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.decode(b"a\xffb"), "a\u{FFFD}b");
+        // A lead byte followed by something that can't continue it
+        assert_eq!(decoder.decode(b"\xe2(c"), "\u{FFFD}(c");
+        assert_eq!(decoder.finish(), "");
+    }
+
+    #[test]
+    fn replaces_a_held_back_start_the_next_read_breaks_off() {
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.decode(b"x\xf0\x9f"), "x");
+        assert_eq!(decoder.decode(b"y"), "\u{FFFD}y");
+    }
+
+    #[test]
+    fn replaces_a_character_truncated_at_eof() {
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.decode(b"end\xf0\x9f\xa6"), "end");
+        assert_eq!(decoder.finish(), "\u{FFFD}");
+    }
+
+    #[test]
+    fn keeps_invalid_bytes_before_a_truncated_tail_apart() {
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.decode(b"\xff\xe2\x9c"), "\u{FFFD}");
+        assert_eq!(decoder.decode(b"\x93"), "✓");
+    }
+}
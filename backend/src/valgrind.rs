@@ -0,0 +1,475 @@
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::HashMap, path::Path};
+use tokio::process::Command;
+
+use crate::sanitizer::StackFrame;
+
+/// How many functions to list in each callgrind ranking.
+const TOP_FUNCTIONS: usize = 15;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ValgrindTool {
+    Memcheck,
+    Callgrind,
+}
+
+impl ValgrindTool {
+    /// Wraps `exe` in valgrind. The tool's own messages go to `log` so they
+    /// don't mix with the program's stderr; callgrind writes its profile to
+    /// `data`.
+    pub fn command(self, exe: &Path, log: &Path, data: &Path) -> Command {
+        let mut cmd = Command::new("valgrind");
+        match self {
+            ValgrindTool::Memcheck => {
+                cmd.args(["--tool=memcheck", "--leak-check=full", "--show-leak-kinds=definite,indirect,possible"]);
+            }
+            ValgrindTool::Callgrind => {
+                cmd.arg("--tool=callgrind").arg(format!("--callgrind-out-file={}", data.display()));
+            }
+        }
+        cmd.arg(format!("--log-file={}", log.display())).arg(exe);
+        cmd
+    }
+}
+
+/// Parsed outcome of a valgrind run.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "tool", rename_all = "snake_case")]
+pub enum Report {
+    Memcheck {
+        errors: Vec<MemoryError>,
+        leaks: Vec<LeakRecord>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        leak_summary: Option<LeakSummary>,
+    },
+    Callgrind {
+        total_instructions: u64,
+        top_inclusive: Vec<FunctionCost>,
+        top_exclusive: Vec<FunctionCost>,
+    },
+}
+
+/// An invalid access, use of uninitialised memory, bad free, etc.
+#[derive(Serialize, Clone, Debug)]
+pub struct MemoryError {
+    pub kind: String,
+    pub frames: Vec<StackFrame>,
+    /// Follow-up lines such as "Address 0x... is 0 bytes inside a block of
+    /// size 4 free'd", each with the stack it refers to.
+    pub details: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LeakRecord {
+    /// `definitely`, `indirectly`, `possibly` or `still reachable`.
+    pub kind: String,
+    pub bytes: u64,
+    pub blocks: u64,
+    pub frames: Vec<StackFrame>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct LeakSummary {
+    pub definitely_lost: LeakTotal,
+    pub indirectly_lost: LeakTotal,
+    pub possibly_lost: LeakTotal,
+    pub still_reachable: LeakTotal,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct LeakTotal {
+    pub bytes: u64,
+    pub blocks: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FunctionCost {
+    pub function: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// Instructions executed in the function itself (exclusive Ir).
+    pub self_instructions: u64,
+    /// Instructions executed in the function and everything it called (inclusive Ir).
+    pub inclusive_instructions: u64,
+}
+
+/// Parses memcheck's text log (the file given to `--log-file`).
+pub fn parse_memcheck(log: &str) -> Report {
+    let mut errors = Vec::new();
+    let mut leaks = Vec::new();
+    let mut leak_summary = None;
+
+    // Every line is prefixed with "==pid== "; reports are separated by lines
+    // with nothing after the prefix.
+    let lines = log.lines().map(strip_pid);
+    let mut blocks: Vec<Vec<&str>> = vec![Vec::new()];
+    for line in lines {
+        if line.trim().is_empty() {
+            blocks.push(Vec::new());
+        } else {
+            blocks.last_mut().unwrap().push(line);
+        }
+    }
+
+    for block in blocks.iter().filter(|b| !b.is_empty()) {
+        let headline = block[0].trim();
+        if headline == "LEAK SUMMARY:" {
+            leak_summary = Some(parse_leak_summary(&block[1..]));
+        } else if let Some(mut record) = parse_leak_headline(headline) {
+            record.frames = number(block[1..].iter().filter_map(|l| parse_frame(l)).collect());
+            leaks.push(record);
+        } else if is_error_headline(headline) {
+            let mut frames = Vec::new();
+            let mut details = Vec::new();
+            for line in &block[1..] {
+                match parse_frame(line) {
+                    // Frames after a detail line belong to that detail (e.g. where
+                    // the block was freed); keep only the first stack as `frames`.
+                    Some(frame) if details.is_empty() => frames.push(frame),
+                    Some(frame) => {
+                        let detail: &mut String = details.last_mut().unwrap();
+                        detail.push_str(&format!("\n  {}", describe(&frame)));
+                    }
+                    None => details.push(line.trim().to_string()),
+                }
+            }
+            errors.push(MemoryError { kind: headline.to_string(), frames: number(frames), details });
+        }
+    }
+
+    Report::Memcheck { errors, leaks, leak_summary }
+}
+
+fn strip_pid(line: &str) -> &str {
+    match line.strip_prefix("==").and_then(|rest| rest.split_once("== ")) {
+        Some((pid, rest)) if pid.chars().all(|c| c.is_ascii_digit()) => rest,
+        _ => line.trim_start_matches(|c: char| c == '=' || c.is_ascii_digit()),
+    }
+}
+
+fn is_error_headline(line: &str) -> bool {
+    [
+        "Invalid ",
+        "Conditional jump",
+        "Use of uninitialised",
+        "Syscall param",
+        "Mismatched free",
+        "Source and destination overlap",
+        "Argument 'size' of function",
+        "Process terminating",
+    ]
+    .iter()
+    .any(|prefix| line.starts_with(prefix))
+}
+
+// "40 bytes in 1 blocks are definitely lost in loss record 1 of 1"
+// "24 (16 direct, 8 indirect) bytes in 1 blocks are definitely lost in ..."
+fn parse_leak_headline(line: &str) -> Option<LeakRecord> {
+    let (amounts, rest) = line.split_once(" blocks are ")?;
+    let kind = rest.split(" in loss record").next()?.trim_end_matches(" lost");
+    let bytes = parse_number(amounts.split_whitespace().next()?)?;
+    let blocks = parse_number(amounts.rsplit(' ').next()?)?;
+    Some(LeakRecord { kind: kind.to_string(), bytes, blocks, frames: Vec::new() })
+}
+
+// "   definitely lost: 40 bytes in 1 blocks"
+fn parse_leak_summary(lines: &[&str]) -> LeakSummary {
+    let mut summary = LeakSummary::default();
+    for line in lines {
+        let Some((label, amounts)) = line.trim().split_once(": ") else {
+            continue;
+        };
+        let mut numbers = amounts.split_whitespace().filter_map(parse_number);
+        let total = LeakTotal {
+            bytes: numbers.next().unwrap_or(0),
+            blocks: numbers.next().unwrap_or(0),
+        };
+        match label {
+            "definitely lost" => summary.definitely_lost = total,
+            "indirectly lost" => summary.indirectly_lost = total,
+            "possibly lost" => summary.possibly_lost = total,
+            "still reachable" => summary.still_reachable = total,
+            _ => {}
+        }
+    }
+    summary
+}
+
+// "   at 0x4C2AB80: malloc (vg_replace_malloc.c:299)"
+// "   by 0x10915B: main::main (main.rs:5)"
+// "   by 0x4E5B0B2: (below main) (libc-start.c:308)"
+// "   by 0x10A2F1: ??? (in /usr/lib/libfoo.so)"
+fn parse_frame(line: &str) -> Option<StackFrame> {
+    let line = line.trim();
+    let rest = line.strip_prefix("at ").or_else(|| line.strip_prefix("by "))?;
+    let (_, rest) = rest.split_once(": ")?;
+
+    let (function, location) = match rest.rfind(" (") {
+        Some(pos) if rest.ends_with(')') => (&rest[..pos], &rest[pos + 2..rest.len() - 1]),
+        _ => (rest, ""),
+    };
+    let (file, line) = match location.rsplit_once(':') {
+        Some((file, line)) if !location.starts_with("in ") => (Some(file.to_string()), line.parse().ok()),
+        _ => (None, None),
+    };
+
+    Some(StackFrame {
+        index: 0,
+        function: function.to_string(),
+        file,
+        line,
+        column: None,
+    })
+}
+
+fn number(mut frames: Vec<StackFrame>) -> Vec<StackFrame> {
+    for (index, frame) in frames.iter_mut().enumerate() {
+        frame.index = index;
+    }
+    frames
+}
+
+fn describe(frame: &StackFrame) -> String {
+    match (&frame.file, frame.line) {
+        (Some(file), Some(line)) => format!("{} ({}:{})", frame.function, file, line),
+        _ => frame.function.clone(),
+    }
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    text.replace(',', "").parse().ok()
+}
+
+/// Parses a callgrind profile (the file given to `--callgrind-out-file`)
+/// into per-function instruction counts.
+pub fn parse_callgrind(data: &str) -> Report {
+    #[derive(Default)]
+    struct Costs {
+        file: Option<String>,
+        own: u64,
+        inclusive: u64,
+    }
+
+    // Callgrind compresses repeated names: "fn=(12) name" defines id 12 and
+    // later lines may say just "fn=(12)".
+    let mut names: HashMap<(&str, String), String> = HashMap::new();
+    let mut resolve = |kind: &'static str, value: &str| -> String {
+        let value = value.trim();
+        if let Some(rest) = value.strip_prefix('(') {
+            if let Some((id, name)) = rest.split_once(')') {
+                let key = (kind, id.to_string());
+                let name = name.trim();
+                if name.is_empty() {
+                    return names.get(&key).cloned().unwrap_or_default();
+                }
+                names.insert(key, name.to_string());
+                return name.to_string();
+            }
+        }
+        value.to_string()
+    };
+
+    let mut functions: HashMap<String, Costs> = HashMap::new();
+    let mut positions = 1;
+    let mut total = None;
+    let mut current_file: Option<String> = None;
+    let mut current_fn: Option<String> = None;
+    let mut in_call = false;
+
+    for line in data.lines() {
+        if let Some(spec) = line.strip_prefix("positions:") {
+            positions = spec.split_whitespace().count().max(1);
+        } else if let Some(value) = line.strip_prefix("summary:").or_else(|| line.strip_prefix("totals:")) {
+            total = value.split_whitespace().next().and_then(parse_number);
+        } else if let Some(value) = line.strip_prefix("fl=") {
+            current_file = Some(resolve("file", value));
+        } else if let Some(value) = line.strip_prefix("fi=").or_else(|| line.strip_prefix("fe=")) {
+            // Inlined code from another file; the function stays the same.
+            resolve("file", value);
+        } else if let Some(value) = line.strip_prefix("cfi=").or_else(|| line.strip_prefix("cfl=")) {
+            resolve("file", value);
+        } else if let Some(value) = line.strip_prefix("fn=") {
+            let name = resolve("fn", value);
+            let entry = functions.entry(name.clone()).or_default();
+            if entry.file.is_none() {
+                entry.file = current_file.clone();
+            }
+            current_fn = Some(name);
+        } else if let Some(value) = line.strip_prefix("cfn=") {
+            resolve("fn", value);
+        } else if line.starts_with("calls=") {
+            in_call = true;
+        } else if line.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-' || c == '*') {
+            let cost = line
+                .split_whitespace()
+                .nth(positions)
+                .and_then(parse_number)
+                .unwrap_or(0);
+            if let Some(costs) = current_fn.as_ref().and_then(|f| functions.get_mut(f)) {
+                // The cost line right after `calls=` is the inclusive cost of
+                // that call, not work done by the caller itself.
+                if !in_call {
+                    costs.own += cost;
+                }
+                costs.inclusive += cost;
+            }
+            in_call = false;
+        }
+    }
+
+    let mut costs: Vec<FunctionCost> = functions
+        .into_iter()
+        .map(|(function, c)| FunctionCost {
+            function,
+            file: c.file.filter(|f| f != "???"),
+            self_instructions: c.own,
+            inclusive_instructions: c.inclusive,
+        })
+        .collect();
+    let total_instructions = total.unwrap_or_else(|| costs.iter().map(|c| c.self_instructions).sum());
+
+    costs.sort_by_key(|c| Reverse(c.inclusive_instructions));
+    let top_inclusive = costs.iter().take(TOP_FUNCTIONS).cloned().collect();
+    costs.sort_by_key(|c| Reverse(c.self_instructions));
+    let top_exclusive = costs.into_iter().take(TOP_FUNCTIONS).collect();
+
+    Report::Callgrind { total_instructions, top_inclusive, top_exclusive }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEMCHECK: &str = "\
+==31== Memcheck, a memory error detector
+==31== Command: ./main
+==31== 
+==31== Invalid read of size 4
+==31==    at 0x10915B: main::main (main.rs:5)
+==31==    by 0x4E5B0B2: (below main) (libc-start.c:308)
+==31==  Address 0x4a4f040 is 0 bytes inside a block of size 4 free'd
+==31==    at 0x4C2CDDB: free (vg_replace_malloc.c:530)
+==31==    by 0x109140: main::main (main.rs:4)
+==31== 
+==31== 24 (16 direct, 8 indirect) bytes in 1 blocks are definitely lost in loss record 2 of 2
+==31==    at 0x4C2AB80: malloc (vg_replace_malloc.c:299)
+==31==    by 0x10A2F1: ??? (in /usr/lib/libfoo.so)
+==31== 
+==31== LEAK SUMMARY:
+==31==    definitely lost: 1,024 bytes in 2 blocks
+==31==    indirectly lost: 8 bytes in 1 blocks
+==31==      possibly lost: 0 bytes in 0 blocks
+==31==    still reachable: 72,704 bytes in 1 blocks
+==31== 
+";
+
+    #[test]
+    fn parses_memcheck_errors_and_leaks() {
+        let Report::Memcheck { errors, leaks, leak_summary } = parse_memcheck(MEMCHECK) else {
+            panic!("not a memcheck report");
+        };
+
+        assert_eq!(errors.len(), 1);
+        let error = &errors[0];
+        assert_eq!(error.kind, "Invalid read of size 4");
+        assert_eq!(error.frames.len(), 2);
+        assert_eq!(error.frames[0].function, "main::main");
+        assert_eq!(error.frames[0].file.as_deref(), Some("main.rs"));
+        assert_eq!(error.frames[0].line, Some(5));
+        assert_eq!(error.frames[1].index, 1);
+        assert_eq!(error.frames[1].function, "(below main)");
+        // The stack after the detail is where the block was freed.
+        assert_eq!(
+            error.details,
+            ["Address 0x4a4f040 is 0 bytes inside a block of size 4 free'd\n  free (vg_replace_malloc.c:530)\n  main::main (main.rs:4)"]
+        );
+
+        assert_eq!(leaks.len(), 1);
+        let leak = &leaks[0];
+        assert_eq!((leak.kind.as_str(), leak.bytes, leak.blocks), ("definitely", 24, 1));
+        assert_eq!(leak.frames[1].function, "???");
+        assert_eq!(leak.frames[1].file, None);
+
+        let summary = leak_summary.expect("leak summary");
+        assert_eq!((summary.definitely_lost.bytes, summary.definitely_lost.blocks), (1024, 2));
+        assert_eq!(summary.indirectly_lost.bytes, 8);
+        assert_eq!(summary.possibly_lost.blocks, 0);
+        assert_eq!(summary.still_reachable.bytes, 72704);
+    }
+
+    #[test]
+    fn strips_pid_prefixes() {
+        assert_eq!(strip_pid("==123== Invalid read"), "Invalid read");
+        assert_eq!(strip_pid("==123==    at 0x1: f (a.rs:1)"), "   at 0x1: f (a.rs:1)");
+        assert_eq!(strip_pid("no prefix"), "no prefix");
+    }
+
+    const CALLGRIND: &str = "\
+version: 1
+creator: callgrind-3.22.0
+positions: line
+events: Ir
+
+fl=(1) /tmp/run_1/main.rs
+fn=(1) main::main
+5 10
+calls=1 0x0 7
+7 300
+6 20
+
+fn=(2) main::work
+7 250
+cfl=(2) ???
+cfn=(3) memcpy
+calls=2 0x0 0
+9 50
+
+fl=(2)
+fn=(3)
+0 50
+
+fl=(1)
+fn=(1)
+8 5
+
+summary: 335
+";
+
+    fn cost<'a>(costs: &'a [FunctionCost], function: &str) -> &'a FunctionCost {
+        costs.iter().find(|cost| cost.function == function).expect(function)
+    }
+
+    #[test]
+    fn parses_callgrind_with_compressed_names() {
+        let Report::Callgrind { total_instructions, top_inclusive, top_exclusive } = parse_callgrind(CALLGRIND) else {
+            panic!("not a callgrind report");
+        };
+        assert_eq!(total_instructions, 335);
+        assert_eq!(top_inclusive.len(), 3);
+
+        // "fn=(1)" later on goes back to main::main
+        let main = cost(&top_inclusive, "main::main");
+        assert_eq!(main.file.as_deref(), Some("/tmp/run_1/main.rs"));
+        assert_eq!((main.self_instructions, main.inclusive_instructions), (35, 335));
+
+        let work = cost(&top_inclusive, "main::work");
+        assert_eq!((work.self_instructions, work.inclusive_instructions), (250, 300));
+
+        // Named in a `cfn=` first, defined by id in a `fn=` later
+        let memcpy = cost(&top_inclusive, "memcpy");
+        assert_eq!(memcpy.file, None);
+        assert_eq!(memcpy.self_instructions, 50);
+
+        assert_eq!(top_inclusive[0].function, "main::main");
+        assert_eq!(top_exclusive[0].function, "main::work");
+    }
+
+    #[test]
+    fn sums_self_costs_without_a_summary() {
+        let Report::Callgrind { total_instructions, .. } = parse_callgrind("fn=(1) f\n1 40\nfn=(2) g\n2 2\n") else {
+            panic!("not a callgrind report");
+        };
+        assert_eq!(total_instructions, 42);
+    }
+}
@@ -0,0 +1,194 @@
+use std::{path::Path, sync::LazyLock, time::Duration};
+use tokio::io::{AsyncRead, AsyncWrite};
+use wasmtime::{Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+use wasmtime_wasi::{
+    pipe::{AsyncReadStream, AsyncWriteStream},
+    preview1::{self, WasiP1Ctx},
+    AsyncStdinStream, AsyncStdoutStream, DirPerms, FilePerms, I32Exit, WasiCtxBuilder,
+};
+
+/// Target the program is compiled for in the wasm mode.
+pub const TARGET: &str = "wasm32-wasip1";
+
+/// How often running guests yield back to the async runtime. This is what
+/// lets the usual wall-clock timeout and client disconnects stop a guest
+/// stuck in a tight loop.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Bytes buffered between the guest's stdout/stderr and our readers.
+const OUTPUT_BUFFER: usize = 64 * 1024;
+
+static ENGINE: LazyLock<Engine> = LazyLock::new(|| {
+    let mut config = wasmtime::Config::new();
+    config.async_support(true).epoch_interruption(true);
+    let engine = Engine::new(&config).expect("failed to create wasm engine");
+
+    let ticker = engine.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(EPOCH_TICK);
+        ticker.increment_epoch();
+    });
+    engine
+});
+
+struct Host {
+    wasi: WasiP1Ctx,
+    limits: StoreLimits,
+}
+
+/// How a guest finished. A trap (out-of-bounds access, `unreachable` after a
+/// panic, hitting the memory cap, ...) isn't an exit code, so it's reported
+/// separately.
+pub enum Outcome {
+    Exited(i32),
+    Trapped(String),
+}
+
+/// Runs the `_start` export of a WASI command module with its stdio connected
+/// to the given streams and `dir` as its working directory (`.`, the only
+/// part of the file system it can see). Linear memory is capped at
+/// `memory_limit` bytes.
+/// The returned future can be dropped at any point to stop the guest.
+pub async fn run(
+    module: &Path,
+    dir: &Path,
+    memory_limit: usize,
+    stdin: impl AsyncRead + Send + Unpin + 'static,
+    stdout: impl AsyncWrite + Send + Unpin + 'static,
+    stderr: impl AsyncWrite + Send + Unpin + 'static,
+) -> Result<Outcome, String> {
+    // Cranelift compilation is CPU bound, keep it off the async workers.
+    let path = module.to_path_buf();
+    let module = tokio::task::spawn_blocking(move || Module::from_file(&ENGINE, path))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("Failed to load module: {}", e))?;
+
+    let wasi = WasiCtxBuilder::new()
+        .args(&["main"])
+        .stdin(AsyncStdinStream::new(AsyncReadStream::new(stdin)))
+        .stdout(AsyncStdoutStream::new(AsyncWriteStream::new(OUTPUT_BUFFER, stdout)))
+        .stderr(AsyncStdoutStream::new(AsyncWriteStream::new(OUTPUT_BUFFER, stderr)))
+        .preopened_dir(dir, ".", DirPerms::all(), FilePerms::all())
+        .map_err(|e| format!("Failed to open working directory: {}", e))?
+        .build_p1();
+    let limits = StoreLimitsBuilder::new()
+        .memory_size(memory_limit)
+        .instances(1)
+        .trap_on_grow_failure(true)
+        .build();
+
+    let mut store = Store::new(&ENGINE, Host { wasi, limits });
+    store.limiter(|host| &mut host.limits);
+    store.epoch_deadline_async_yield_and_update(1);
+
+    let mut linker = Linker::new(&ENGINE);
+    preview1::add_to_linker_async(&mut linker, |host: &mut Host| &mut host.wasi)
+        .map_err(|e| e.to_string())?;
+
+    let instance = linker
+        .instantiate_async(&mut store, &module)
+        .await
+        .map_err(|e| format!("Failed to instantiate module: {}", e))?;
+    let start = instance
+        .get_typed_func::<(), ()>(&mut store, "_start")
+        .map_err(|e| format!("Module has no entry point: {}", e))?;
+
+    match start.call_async(&mut store, ()).await {
+        Ok(()) => Ok(Outcome::Exited(0)),
+        Err(e) => match e.downcast_ref::<I32Exit>() {
+            Some(exit) => Ok(Outcome::Exited(exit.0)),
+            None => Ok(Outcome::Trapped(e.root_cause().to_string())),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    const PAGE: usize = 64 * 1024;
+
+    /// Runs the module `wat` with no input and returns how it finished and
+    /// what it printed on stdout.
+    async fn run_wat(wat: &str, memory_limit: usize) -> (Result<Outcome, String>, String) {
+        let dir = std::env::temp_dir().join(format!("wasm_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let module = dir.join("main.wat");
+        std::fs::write(&module, wat).unwrap();
+        let (stdout, mut printed) = tokio::io::duplex(OUTPUT_BUFFER);
+        let outcome = run(&module, &dir, memory_limit, tokio::io::empty(), stdout, tokio::io::sink()).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut output = String::new();
+        printed.read_to_string(&mut output).await.unwrap();
+        (outcome, output)
+    }
+
+    #[tokio::test]
+    async fn reports_exit_codes_and_output() {
+        let wat = r#"(module
+            (import "wasi_snapshot_preview1" "fd_write" (func $write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "hi\n")
+            (func (export "_start")
+                (i32.store (i32.const 0) (i32.const 16))
+                (i32.store (i32.const 4) (i32.const 3))
+                (drop (call $write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#;
+        let (outcome, output) = run_wat(wat, PAGE).await;
+        assert!(matches!(outcome, Ok(Outcome::Exited(0))));
+        assert_eq!(output, "hi\n");
+
+        let wat = r#"(module
+            (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+            (memory (export "memory") 1)
+            (func (export "_start") (call $exit (i32.const 3))))"#;
+        assert!(matches!(run_wat(wat, PAGE).await.0, Ok(Outcome::Exited(3))));
+    }
+
+    #[tokio::test]
+    async fn maps_traps_to_their_cause() {
+        let wat = r#"(module (memory (export "memory") 1) (func (export "_start") unreachable))"#;
+        match run_wat(wat, PAGE).await.0 {
+            Ok(Outcome::Trapped(message)) => assert!(message.contains("unreachable"), "{}", message),
+            _ => panic!("expected a trap"),
+        }
+    }
+
+    #[tokio::test]
+    async fn traps_when_memory_grows_past_the_limit() {
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "_start") (drop (memory.grow (i32.const 1)))))"#;
+        assert!(matches!(run_wat(wat, 2 * PAGE).await.0, Ok(Outcome::Exited(0))));
+        match run_wat(wat, PAGE).await.0 {
+            Ok(Outcome::Trapped(message)) => assert!(message.contains("memory"), "{}", message),
+            _ => panic!("expected a trap"),
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_modules_over_the_limit_or_without_an_entry_point() {
+        let wat = r#"(module (memory (export "memory") 4) (func (export "_start")))"#;
+        let outcome = run_wat(wat, PAGE).await.0;
+        assert!(matches!(&outcome, Err(message) if message.starts_with("Failed to instantiate module")));
+
+        let wat = r#"(module (memory (export "memory") 1) (func (export "main")))"#;
+        let outcome = run_wat(wat, PAGE).await.0;
+        assert!(matches!(&outcome, Err(message) if message.starts_with("Module has no entry point")));
+    }
+
+    #[tokio::test]
+    async fn a_guest_in_a_tight_loop_can_be_stopped() {
+        let dir = std::env::temp_dir().join(format!("wasm_test_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let module = dir.join("main.wat");
+        std::fs::write(&module, r#"(module (memory (export "memory") 1) (func (export "_start") (loop $spin (br $spin))))"#)
+            .unwrap();
+        let guest = run(&module, &dir, PAGE, tokio::io::empty(), tokio::io::sink(), tokio::io::sink());
+        let stopped = tokio::time::timeout(Duration::from_millis(500), guest).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(stopped.is_err());
+    }
+}
@@ -16,10 +16,63 @@ import BackgroundAnimation from "../components/BackgroundAnimation";
 import axios from "axios";
 import { useAuth } from "../context/AuthContext";
 
+// Event sent by the /ws endpoint, tagged by `type`
+interface RunEvent {
+  type: string;
+  data?: string;
+  message?: string;
+  level?: string;
+  rendered?: string;
+  exit_code?: number | null;
+  timed_out?: boolean;
+  stdin_timed_out?: boolean;
+  duration_ms?: number;
+  session_id?: string;
+  // output_limit: which limit was hit, "total" or "rate"
+  limit?: string;
+  discarded_bytes?: number;
+  killed?: boolean;
+}
+
+// Reconnect attempts for a run whose socket dropped before it finished
+const MAX_RECONNECTS = 5;
+
+// Events after which the server sends nothing more for a run
+const FINAL_EVENTS = ["exit", "error", "compile_error"];
+
+// Turns a run event into text for the terminal pane
+const formatRunEvent = (message: RunEvent): string => {
+  switch (message.type) {
+    case "stdout":
+    case "stderr":
+    case "compile_error":
+      return message.data ?? "";
+    case "diagnostic":
+      return (message.rendered ?? `${message.level}: ${message.message}`) + "\n";
+    case "error":
+      return `${message.message}\n`;
+    case "output_limit": {
+      const limit = message.limit === "rate" ? "rate limit" : "size limit";
+      const action = message.killed ? ", program stopped" : "";
+      return `\n[Output over the ${limit}, ${message.discarded_bytes ?? 0} bytes discarded${action}]\n`;
+    }
+    case "exit":
+      if (message.timed_out) {
+        return `\n[Time limit exceeded after ${message.duration_ms} ms]\n`;
+      }
+      if (message.stdin_timed_out) {
+        return `\n[Stopped after waiting too long for input]\n`;
+      }
+      return `\n[Process exited with code ${message.exit_code ?? "unknown"}]\n`;
+    default:
+      return "";
+  }
+};
+
 const EditorPage: React.FC = () => {
   const { id } = useParams<{ id: string }>();
   const navigate = useNavigate();
-  const { isAuthenticated } = useAuth();
+  const { isAuthenticated, token } = useAuth();
   
   const [code, setCode] = useState<string>(`use std::io;
 use std::io::Write;
@@ -68,43 +121,85 @@ fn main() {
   // Cleanup socket on unmount
   useEffect(() => {
     return () => {
-      if (socketRef.current) {
-        socketRef.current.close();
-      }
+      const socket = socketRef.current;
+      socketRef.current = null;
+      socket?.close();
     };
   }, []);
 
   const handleRun = () => {
-    if (socketRef.current) {
-      socketRef.current.close();
-    }
+    const previous = socketRef.current;
+    socketRef.current = null;
+    previous?.close();
 
     setIsLoading(true);
     setOutput("");
 
-    const ws = new WebSocket("ws://localhost:3001/ws");
-    socketRef.current = ws;
+    // The run's session, and how many of its events arrived so far
+    let sessionId: string | null = null;
+    let offset = 0;
+    let finished = false;
+    let reconnects = 0;
 
-    ws.onopen = () => {
-      // Send the code as the first message
-      ws.send(JSON.stringify({ code }));
-    };
+    const connect = () => {
+      const url = sessionId
+        ? `ws://localhost:3001/ws?session_id=${sessionId}&offset=${offset}`
+        : "ws://localhost:3001/ws";
+      const ws = new WebSocket(url);
+      socketRef.current = ws;
 
-    ws.onmessage = (event) => {
-      const data = event.data;
-      setOutput((prev) => prev + data);
-    };
+      ws.onopen = () => {
+        // Send the code as the first message, unless reattaching. Signed-in
+        // users' runs go into their history.
+        if (!sessionId) {
+          ws.send(JSON.stringify(token ? { code, token } : { code }));
+        }
+      };
 
-    ws.onclose = () => {
-      setIsLoading(false);
-      socketRef.current = null;
-    };
+      ws.onmessage = (event) => {
+        const message: RunEvent = JSON.parse(event.data);
+        if (message.type === "session") {
+          // Reattaching replays the same id; only a new run starts counting
+          // reconnects over
+          if (message.session_id !== sessionId) {
+            sessionId = message.session_id ?? null;
+            reconnects = 0;
+          }
+          return;
+        }
+        offset++;
+        if (FINAL_EVENTS.includes(message.type)) {
+          finished = true;
+        }
+        setOutput((prev) => prev + formatRunEvent(message));
+      };
+
+      ws.onclose = () => {
+        // Closed by a newer run, or on unmount
+        if (socketRef.current !== ws) {
+          return;
+        }
+        if (!finished && sessionId && reconnects < MAX_RECONNECTS) {
+          reconnects++;
+          setTimeout(() => {
+            if (socketRef.current === ws) {
+              connect();
+            }
+          }, 1000 * reconnects);
+          return;
+        }
+        setIsLoading(false);
+        socketRef.current = null;
+      };
 
-    ws.onerror = (error) => {
-      console.error("WebSocket error:", error);
-      setOutput((prev) => prev + "\nError connecting to server.\n");
-      setIsLoading(false);
+      ws.onerror = (error) => {
+        console.error("WebSocket error:", error);
+        if (!sessionId) {
+          setOutput((prev) => prev + "\nError connecting to server.\n");
+        }
+      };
     };
+    connect();
   };
 
   const handleInput = (e: React.KeyboardEvent<HTMLInputElement>) => {
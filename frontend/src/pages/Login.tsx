@@ -24,7 +24,11 @@ const Login: React.FC = () => {
       login(response.data.token, username);
       navigate('/dashboard');
     } catch (err: any) {
-      setError(err.response?.data?.error || 'Failed to login');
+      if (err.response?.status === 429) {
+        setError(`Too many attempts, try again in ${err.response.headers['retry-after']} s`);
+      } else {
+        setError(err.response?.data?.error || 'Failed to login');
+      }
     }
   };
 
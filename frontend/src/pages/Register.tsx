@@ -27,7 +27,11 @@ const Register: React.FC = () => {
       
       navigate('/login');
     } catch (err: any) {
-      setError(err.response?.data?.error || 'Failed to register');
+      if (err.response?.status === 429) {
+        setError(`Too many attempts, try again in ${err.response.headers['retry-after']} s`);
+      } else {
+        setError(err.response?.data?.error || 'Failed to register');
+      }
     }
   };
 